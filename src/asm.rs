@@ -0,0 +1,596 @@
+//! A small x86-64 assembler, covering just the instructions the generated
+//! code uses. Operands are 64 bits unless the method says otherwise, and
+//! jumps go to labels which are resolved once everything has been emitted.
+
+use std::collections::HashMap;
+use std::mem;
+
+use helpers::Helper;
+
+/// The registers generated code uses, numbered as in the encodings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R11 = 11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A memory operand, `[base + disp]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Mem {
+    pub base: Reg,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn new(base: Reg, disp: i32) -> Self {
+        Mem { base, disp }
+    }
+}
+
+/// Condition codes, numbered as in the `jcc` encodings.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Cond {
+    /// Below, unsigned.
+    B = 0x2,
+    /// Above or equal, unsigned.
+    Ae = 0x3,
+    E = 0x4,
+    /// Above, unsigned.
+    A = 0x7,
+    /// Sign, i.e. negative.
+    S = 0x8,
+}
+
+/// A place in the code which jumps can go to. It doesn't have to be bound
+/// before it's used.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Label(usize);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Width {
+    Rel8,
+    Rel32,
+}
+
+/// A displacement which is filled in once its label is bound. It's always
+/// the last thing in its instruction, so it's relative to its own end.
+struct Fixup {
+    at: usize,
+    label: Label,
+    width: Width,
+}
+
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<Fixup>,
+    // Labels for the program's own marks, so jumps can refer to them by name.
+    names: HashMap<::Label, Label>,
+    // Labels for the stubs which call each helper.
+    stubs: HashMap<Helper, Label>,
+    exit: Option<Label>,
+    // The key constants from the program are hidden with, if they are.
+    blinding: Option<u64>,
+    // Whether to call helpers directly rather than through stubs, which
+    // makes more code; it's only for comparing the two.
+    inline_helpers: bool,
+    // Whether the program counts down `Context::steps_left`, so that it can
+    // be stopped if it runs for too long.
+    limit_steps: bool,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            names: HashMap::new(),
+            stubs: HashMap::new(),
+            exit: None,
+            blinding: None,
+            inline_helpers: false,
+            limit_steps: false,
+        }
+    }
+
+    /// How much code has been emitted so far.
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// The label for one of the program's marks. Asking twice gives the same
+    /// label.
+    pub fn named(&mut self, name: &::Label) -> Label {
+        if let Some(&label) = self.names.get(name) {
+            return label;
+        }
+        let label = self.new_label();
+        self.names.insert(name.clone(), label);
+        label
+    }
+
+    /// The label for the stub which calls a helper. Asking twice gives the
+    /// same label.
+    pub fn stub(&mut self, helper: Helper) -> Label {
+        if let Some(&label) = self.stubs.get(&helper) {
+            return label;
+        }
+        let label = self.new_label();
+        self.stubs.insert(helper, label);
+        label
+    }
+
+    /// Every helper which has been asked for a stub, with its label.
+    pub fn stubs(&self) -> Vec<(Helper, Label)> {
+        let mut stubs = self.stubs.iter().map(|(&helper, &label)| (helper, label)).collect::<Vec<_>>();
+        stubs.sort_by_key(|&(helper, _)| helper);
+        stubs
+    }
+
+    /// The label for the one way out of the code.
+    pub fn exit(&mut self) -> Label {
+        if let Some(label) = self.exit {
+            return label;
+        }
+        let label = self.new_label();
+        self.exit = Some(label);
+        label
+    }
+
+    /// Hides every constant from the program from now on, so that a program
+    /// can't choose what bytes end up in executable memory. See `mov_const`.
+    pub fn blind(&mut self, key: u64) {
+        self.blinding = Some(key);
+    }
+
+    pub fn is_blinding(&self) -> bool {
+        self.blinding.is_some()
+    }
+
+    pub fn inline_helpers(&self) -> bool {
+        self.inline_helpers
+    }
+
+    #[cfg(test)]
+    pub fn set_inline_helpers(&mut self, inline: bool) {
+        self.inline_helpers = inline;
+    }
+
+    /// Counts the program's steps from now on, stopping it when it runs out.
+    /// See `command::step`.
+    pub fn limit_steps(&mut self) {
+        self.limit_steps = true;
+    }
+
+    pub fn is_limiting_steps(&self) -> bool {
+        self.limit_steps
+    }
+
+    /// Puts a label here. Binding it again moves it, and everything which
+    /// uses it goes to the last place it was bound.
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Resolves every jump, returning the code. Fails with the first mark
+    /// which is jumped to but never bound; every other label must be bound.
+    pub fn finish(mut self) -> Result<Vec<u8>, ::Label> {
+        for fixup in mem::take(&mut self.fixups) {
+            let target = match self.labels[fixup.label.0] {
+                Some(target) => target,
+                None => {
+                    let name = self.names.iter().find(|&(_, &l)| l == fixup.label);
+                    return Err(name.expect("unbound label").0.clone());
+                }
+            };
+            let size = if fixup.width == Width::Rel8 { 1 } else { 4 };
+            let offset = target as i64 - (fixup.at + size) as i64;
+            match fixup.width {
+                Width::Rel8 => {
+                    assert!(offset as i8 as i64 == offset, "short jump out of range");
+                    self.code[fixup.at] = offset as u8;
+                }
+                Width::Rel32 => {
+                    let offset = offset as i32;
+                    self.code[fixup.at..fixup.at + 4].copy_from_slice(&offset.to_le_bytes());
+                }
+            }
+        }
+        Ok(self.code)
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// The REX prefix, if one is needed: for 64-bit operands, or to reach
+    /// r8 to r15.
+    fn rex(&mut self, wide: bool, reg: u8, base: u8) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (base >> 3);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    /// An instruction on two registers, or a register and an opcode
+    /// extension in `reg`.
+    fn op_reg(&mut self, opcode: &[u8], reg: u8, rm: Reg) {
+        self.rex(true, reg, rm.number());
+        self.bytes(opcode);
+        self.byte(0xc0 | (reg & 7) << 3 | (rm.number() & 7));
+    }
+
+    /// An instruction on a register, or opcode extension, and memory.
+    fn op_mem(&mut self, wide: bool, opcode: &[u8], reg: u8, mem: Mem) {
+        self.rex(wide, reg, mem.base.number());
+        self.bytes(opcode);
+        let base = mem.base.number() & 7;
+        // [rbp] and [r13] can only be encoded with a displacement.
+        let (mode, size) = if mem.disp == 0 && base != 5 {
+            (0x00, 0)
+        } else if mem.disp as i8 as i32 == mem.disp {
+            (0x40, 1)
+        } else {
+            (0x80, 4)
+        };
+        self.byte(mode | (reg & 7) << 3 | base);
+        // [rsp] and [r12] need a SIB byte.
+        if base == 4 {
+            self.byte(0x24);
+        }
+        self.bytes(&mem.disp.to_le_bytes()[..size]);
+    }
+
+    fn rel(&mut self, label: Label, width: Width) {
+        self.fixups.push(Fixup {
+            at: self.code.len(),
+            label,
+            width,
+        });
+        match width {
+            Width::Rel8 => self.byte(0),
+            Width::Rel32 => self.bytes(&[0; 4]),
+        }
+    }
+
+    /// mov dst, src
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(&[0x89], src.number(), dst);
+    }
+
+    /// mov dst, imm64
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, dst.number());
+        self.byte(0xb8 + (dst.number() & 7));
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// mov dst, imm64 for a constant which came from the program. When
+    /// blinding, it's XORed with the key, which is undone at run time using
+    /// `r11`, so the constant itself never appears in the code.
+    pub fn mov_const(&mut self, dst: Reg, imm: u64) {
+        match self.blinding {
+            Some(key) => {
+                assert!(dst != Reg::R11, "blinding needs r11");
+                self.mov_imm(dst, imm ^ key);
+                self.mov_imm(Reg::R11, key);
+                self.xor(dst, Reg::R11);
+            }
+            None => self.mov_imm(dst, imm),
+        }
+    }
+
+    /// mov dst, [mem]
+    pub fn load(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x8b], dst.number(), mem);
+    }
+
+    /// mov [mem], src
+    pub fn store(&mut self, mem: Mem, src: Reg) {
+        self.op_mem(true, &[0x89], src.number(), mem);
+    }
+
+    /// mov byte [mem], imm8
+    pub fn store_byte(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0xc6], 0, mem);
+        self.byte(imm);
+    }
+
+    /// lea dst, [mem]
+    pub fn lea(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x8d], dst.number(), mem);
+    }
+
+    /// add dst, src
+    pub fn add(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(&[0x01], src.number(), dst);
+    }
+
+    /// sub dst, src
+    pub fn sub(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(&[0x29], src.number(), dst);
+    }
+
+    /// xor dst, src
+    pub fn xor(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(&[0x31], src.number(), dst);
+    }
+
+    /// add dst, [mem]
+    pub fn add_mem(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x03], dst.number(), mem);
+    }
+
+    /// add dst, imm
+    pub fn add_imm(&mut self, dst: Reg, imm: i32) {
+        self.arith_imm(0, dst, imm);
+    }
+
+    /// sub dst, imm
+    pub fn sub_imm(&mut self, dst: Reg, imm: i32) {
+        self.arith_imm(5, dst, imm);
+    }
+
+    fn arith_imm(&mut self, extension: u8, dst: Reg, imm: i32) {
+        if imm as i8 as i32 == imm {
+            self.op_reg(&[0x83], extension, dst);
+            self.byte(imm as u8);
+        } else {
+            self.op_reg(&[0x81], extension, dst);
+            self.bytes(&imm.to_le_bytes());
+        }
+    }
+
+    /// imul dst, src
+    pub fn imul(&mut self, dst: Reg, src: Reg) {
+        self.op_reg(&[0x0f, 0xaf], dst.number(), src);
+    }
+
+    /// idiv src ; divides rdx:rax, leaving the quotient in rax and the
+    /// remainder in rdx
+    pub fn idiv(&mut self, src: Reg) {
+        self.op_reg(&[0xf7], 7, src);
+    }
+
+    /// cqo ; sign extends rax into rdx:rax
+    pub fn cqo(&mut self) {
+        self.bytes(&[0x48, 0x99]);
+    }
+
+    /// shl dst, imm8
+    pub fn shl_imm(&mut self, dst: Reg, imm: u8) {
+        self.op_reg(&[0xc1], 4, dst);
+        self.byte(imm);
+    }
+
+    /// cmp lhs, [mem]
+    pub fn cmp_mem(&mut self, lhs: Reg, mem: Mem) {
+        self.op_mem(true, &[0x3b], lhs.number(), mem);
+    }
+
+    /// cmp byte [mem], imm8
+    pub fn cmp_byte(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0x80], 7, mem);
+        self.byte(imm);
+    }
+
+    /// test lhs, rhs
+    pub fn test(&mut self, lhs: Reg, rhs: Reg) {
+        self.op_reg(&[0x85], rhs.number(), lhs);
+    }
+
+    pub fn push(&mut self, src: Reg) {
+        self.rex(false, 0, src.number());
+        self.byte(0x50 + (src.number() & 7));
+    }
+
+    pub fn pop(&mut self, dst: Reg) {
+        self.rex(false, 0, dst.number());
+        self.byte(0x58 + (dst.number() & 7));
+    }
+
+    /// jmp rel32
+    pub fn jmp(&mut self, label: Label) {
+        self.byte(0xe9);
+        self.rel(label, Width::Rel32);
+    }
+
+    /// jmp [mem]
+    pub fn jmp_mem(&mut self, mem: Mem) {
+        self.op_mem(false, &[0xff], 4, mem);
+    }
+
+    /// jmp rel8 ; the label has to be close by
+    pub fn jmp_short(&mut self, label: Label) {
+        self.byte(0xeb);
+        self.rel(label, Width::Rel8);
+    }
+
+    /// jcc rel32
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.bytes(&[0x0f, 0x80 + cond as u8]);
+        self.rel(label, Width::Rel32);
+    }
+
+    /// jcc rel8 ; the label has to be close by
+    pub fn jcc_short(&mut self, cond: Cond, label: Label) {
+        self.byte(0x70 + cond as u8);
+        self.rel(label, Width::Rel8);
+    }
+
+    /// call rel32
+    pub fn call(&mut self, label: Label) {
+        self.byte(0xe8);
+        self.rel(label, Width::Rel32);
+    }
+
+    /// call [mem]
+    pub fn call_mem(&mut self, mem: Mem) {
+        self.op_mem(false, &[0xff], 2, mem);
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+
+    /// ret imm16 ; also drops that many bytes from the stack
+    pub fn ret_imm(&mut self, imm: u16) {
+        self.byte(0xc2);
+        self.bytes(&imm.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Reg::*;
+
+    /// Assembles some code which doesn't jump anywhere.
+    fn encode<F: FnOnce(&mut Assembler)>(f: F) -> Vec<u8> {
+        let mut a = Assembler::new();
+        f(&mut a);
+        a.finish().unwrap()
+    }
+
+    #[test]
+    fn test_registers() {
+        assert_eq!(encode(|a| a.mov(Rbx, Rax)), vec![0x48, 0x89, 0xc3]);
+        assert_eq!(encode(|a| a.mov(R12, Rax)), vec![0x49, 0x89, 0xc4]);
+        assert_eq!(encode(|a| a.mov(Rsi, R12)), vec![0x4c, 0x89, 0xe6]);
+        assert_eq!(encode(|a| a.mov(Rbp, Rsp)), vec![0x48, 0x89, 0xe5]);
+        assert_eq!(encode(|a| a.mov_imm(R14, 1)), vec![0x49, 0xbe, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(|a| a.mov_imm(Rdi, 0x0102030405060708)),
+                   vec![0x48, 0xbf, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(encode(|a| a.push(Rbp)), vec![0x55]);
+        assert_eq!(encode(|a| a.push(R12)), vec![0x41, 0x54]);
+        assert_eq!(encode(|a| a.pop(R15)), vec![0x41, 0x5f]);
+        assert_eq!(encode(|a| a.pop(Rbx)), vec![0x5b]);
+        assert_eq!(encode(|a| a.call_mem(Mem::new(Rax, 0x18))), vec![0xff, 0x50, 0x18]);
+        assert_eq!(encode(|a| a.jmp_mem(Mem::new(Rax, 0x18))), vec![0xff, 0x60, 0x18]);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(encode(|a| a.add(Rax, R12)), vec![0x4c, 0x01, 0xe0]);
+        assert_eq!(encode(|a| a.sub(Rax, R12)), vec![0x4c, 0x29, 0xe0]);
+        assert_eq!(encode(|a| a.xor(Rsi, R11)), vec![0x4c, 0x31, 0xde]);
+        assert_eq!(encode(|a| a.imul(Rax, R12)), vec![0x49, 0x0f, 0xaf, 0xc4]);
+        assert_eq!(encode(|a| a.imul(R13, Rbx)), vec![0x4c, 0x0f, 0xaf, 0xeb]);
+        assert_eq!(encode(|a| a.idiv(R12)), vec![0x49, 0xf7, 0xfc]);
+        assert_eq!(encode(|a| a.cqo()), vec![0x48, 0x99]);
+        assert_eq!(encode(|a| a.add_imm(R15, 8)), vec![0x49, 0x83, 0xc7, 0x08]);
+        assert_eq!(encode(|a| a.sub_imm(Rsp, 8)), vec![0x48, 0x83, 0xec, 0x08]);
+        assert_eq!(encode(|a| a.add_imm(Rax, 0x1000)),
+                   vec![0x48, 0x81, 0xc0, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(encode(|a| a.shl_imm(Rax, 4)), vec![0x48, 0xc1, 0xe0, 0x04]);
+        assert_eq!(encode(|a| a.test(Rax, Rax)), vec![0x48, 0x85, 0xc0]);
+    }
+
+    #[test]
+    fn test_memory() {
+        assert_eq!(encode(|a| a.cmp_mem(R15, Mem::new(Rax, 0))), vec![0x4c, 0x3b, 0x38]);
+        assert_eq!(encode(|a| a.store(Mem::new(R15, 0), Rsi)), vec![0x49, 0x89, 0x37]);
+        assert_eq!(encode(|a| a.load(Rax, Mem::new(R15, 0))), vec![0x49, 0x8b, 0x07]);
+        assert_eq!(encode(|a| a.add_mem(Rax, Mem::new(Rcx, 0))), vec![0x48, 0x03, 0x01]);
+        assert_eq!(encode(|a| a.lea(Rsp, Mem::new(Rbp, -0x28))), vec![0x48, 0x8d, 0x65, 0xd8]);
+        assert_eq!(encode(|a| a.lea(Rax, Mem::new(R15, -0x1000))),
+                   vec![0x49, 0x8d, 0x87, 0x00, 0xf0, 0xff, 0xff]);
+        assert_eq!(encode(|a| a.store_byte(Mem::new(Rax, 8), 1)), vec![0xc6, 0x40, 0x08, 0x01]);
+        assert_eq!(encode(|a| a.cmp_byte(Mem::new(Rax, 8), 0)), vec![0x80, 0x78, 0x08, 0x00]);
+        // The awkward bases.
+        assert_eq!(encode(|a| a.load(Rax, Mem::new(Rsp, 0))), vec![0x48, 0x8b, 0x04, 0x24]);
+        assert_eq!(encode(|a| a.load(Rax, Mem::new(R12, 0))), vec![0x49, 0x8b, 0x04, 0x24]);
+        assert_eq!(encode(|a| a.load(Rax, Mem::new(Rbp, 0))), vec![0x48, 0x8b, 0x45, 0x00]);
+        assert_eq!(encode(|a| a.load(Rax, Mem::new(R13, 0))), vec![0x49, 0x8b, 0x45, 0x00]);
+    }
+
+    #[test]
+    fn test_jumps() {
+        assert_eq!(encode(|a| a.ret()), vec![0xc3]);
+        assert_eq!(encode(|a| a.ret_imm(8)), vec![0xc2, 0x08, 0x00]);
+
+        // Forwards, and short.
+        assert_eq!(encode(|a| {
+                              let end = a.new_label();
+                              a.jcc_short(Cond::Ae, end);
+                              a.ret();
+                              a.bind(end);
+                          }),
+                   vec![0x73, 0x01, 0xc3]);
+        // Backwards.
+        assert_eq!(encode(|a| {
+                              let top = a.new_label();
+                              a.bind(top);
+                              a.ret();
+                              a.jcc(Cond::E, top);
+                              a.call(top);
+                              a.jmp_short(top);
+                          }),
+                   vec![0xc3,
+                        0x0f, 0x84, 0xf9, 0xff, 0xff, 0xff,
+                        0xe8, 0xf4, 0xff, 0xff, 0xff,
+                        0xeb, 0xf2]);
+    }
+
+    #[test]
+    fn test_named_labels() {
+        let name = ::Label::Name(vec![true]);
+        let mut a = Assembler::new();
+        let label = a.named(&name);
+        assert_eq!(a.named(&name), label);
+        a.jmp(label);
+        a.bind(label);
+        assert_eq!(a.finish(), Ok(vec![0xe9, 0, 0, 0, 0]));
+
+        let mut a = Assembler::new();
+        let label = a.named(&name);
+        a.jmp(label);
+        assert_eq!(a.finish(), Err(name));
+    }
+
+    #[test]
+    #[should_panic(expected = "short jump out of range")]
+    fn test_short_jump_too_far() {
+        let mut a = Assembler::new();
+        let end = a.new_label();
+        a.jmp_short(end);
+        for _ in 0..0x80 {
+            a.ret();
+        }
+        a.bind(end);
+        a.finish().unwrap();
+    }
+}
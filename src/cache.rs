@@ -0,0 +1,317 @@
+//! Keeps compiled programs on disk, so running a program again doesn't mean
+//! compiling it again. This works because generated code doesn't contain any
+//! addresses: it's given the context and the helpers when it's called.
+//!
+//! Entries are keyed by a hash of the source, the options it was compiled
+//! with, and the compiler's version, so a stale entry is never found rather
+//! than needing to be thrown out.
+//!
+//! Whatever is in the cache gets run, so anyone who can write to it can run
+//! code as whoever uses it. That's why it's only used when asked for.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+
+use cli::Options;
+use wsstd::{Address, Context};
+use Label;
+
+/// What every cache file starts with.
+const MAGIC: &[u8] = b"wsjit\x01";
+
+/// FNV-1a, which unlike the standard library's hasher is guaranteed to give
+/// the same answer from one build to the next.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        // Keep consecutive fields apart.
+        self.0 = (self.0 ^ 0xff).wrapping_mul(0x0100_0000_01b3);
+    }
+}
+
+/// Goes up whenever generated code, or what it expects of the context and
+/// the helpers, changes in a way the crate version might not show, so that
+/// earlier entries miss.
+const CODE_VERSION: u32 = 1;
+
+/// The key for a program compiled from `source` with `options`.
+pub fn key(source: &[u8], options: &Options) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hash.write(&CODE_VERSION.to_le_bytes());
+    hash.write(&[cfg!(feature = "extensions") as u8, options.opt_level]);
+    hash.write(format!("{:?}", options.parse).as_bytes());
+    hash.write(source);
+    hash.0
+}
+
+/// A compiled program, along with what it told the context about itself
+/// while it was being compiled.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Compiled {
+    pub code: Vec<u8>,
+    pub labels: HashMap<Label, Address>,
+    pub trace_names: Vec<String>,
+}
+
+impl Compiled {
+    /// Takes the labels and names from the context the code was compiled
+    /// against.
+    pub fn new(code: Vec<u8>, context: &Context) -> Self {
+        #[cfg(feature = "extensions")]
+        let trace_names = context.trace_names.clone();
+        #[cfg(not(feature = "extensions"))]
+        let trace_names = vec![];
+        Compiled {
+            code,
+            labels: context.labels.clone(),
+            trace_names,
+        }
+    }
+
+    /// Tells a context what compiling the program would have.
+    pub fn install(&self, context: &mut Context) {
+        context.labels = self.labels.clone();
+        #[cfg(feature = "extensions")]
+        {
+            context.trace_names = self.trace_names.clone();
+        }
+    }
+
+    fn encode(&self, key: u64) -> Vec<u8> {
+        fn bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+            out.extend(&(bytes.len() as u64).to_le_bytes());
+            out.extend(bytes);
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.extend(&key.to_le_bytes());
+        bytes(&mut out, &self.code);
+        out.extend(&(self.labels.len() as u64).to_le_bytes());
+        for (label, &address) in &self.labels {
+            match *label {
+                Label::Name(ref bits) => {
+                    out.push(0);
+                    bytes(&mut out, &bits.iter().map(|&b| b as u8).collect::<Vec<_>>());
+                }
+                Label::Translated(n) => {
+                    out.push(1);
+                    out.extend(&(n as u64).to_le_bytes());
+                }
+            }
+            out.extend(&(address as u64).to_le_bytes());
+        }
+        out.extend(&(self.trace_names.len() as u64).to_le_bytes());
+        for name in &self.trace_names {
+            bytes(&mut out, name.as_bytes());
+        }
+        out
+    }
+
+    /// Reads back what `encode` wrote, failing if it's anything else.
+    fn decode(data: &[u8], key: u64) -> Option<Self> {
+        struct Reader<'a>(&'a [u8]);
+
+        impl<'a> Reader<'a> {
+            fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+                if n > self.0.len() {
+                    return None;
+                }
+                let (taken, rest) = self.0.split_at(n);
+                self.0 = rest;
+                Some(taken)
+            }
+
+            fn u64(&mut self) -> Option<u64> {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(self.take(8)?);
+                Some(u64::from_le_bytes(bytes))
+            }
+
+            fn bytes(&mut self) -> Option<&'a [u8]> {
+                let n = self.u64()?;
+                self.take(n as usize)
+            }
+        }
+
+        let mut r = Reader(data);
+        if r.take(MAGIC.len())? != MAGIC || r.u64()? != key {
+            return None;
+        }
+        let code = r.bytes()?.to_vec();
+        let mut labels = HashMap::new();
+        for _ in 0..r.u64()? {
+            let label = match r.take(1)?[0] {
+                0 => Label::Name(r.bytes()?.iter().map(|&b| b != 0).collect()),
+                1 => Label::Translated(r.u64()? as Address),
+                _ => return None,
+            };
+            labels.insert(label, r.u64()? as Address);
+        }
+        let mut trace_names = vec![];
+        for _ in 0..r.u64()? {
+            trace_names.push(String::from_utf8(r.bytes()?.to_vec()).ok()?);
+        }
+        if !r.0.is_empty() {
+            return None;
+        }
+        Some(Compiled {
+            code,
+            labels,
+            trace_names,
+        })
+    }
+}
+
+/// A directory of compiled programs.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// The user's cache directory, if they have one.
+    pub fn open() -> Option<Self> {
+        let dir = env::var_os("XDG_CACHE_HOME")
+                      .filter(|dir| !dir.is_empty())
+                      .map(PathBuf::from)
+                      .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(Cache::new(dir.join("whitespace")))
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}", key))
+    }
+
+    /// Finds an earlier compilation. Anything wrong with the entry is treated
+    /// as not finding it.
+    pub fn load(&self, key: u64) -> Option<Compiled> {
+        let mut data = vec![];
+        File::open(self.path(key)).ok()?.read_to_end(&mut data).ok()?;
+        Compiled::decode(&data, key)
+    }
+
+    /// Keeps a compilation for later. The entry is written under another name
+    /// first, so that another run never sees half of it.
+    pub fn store(&self, key: u64, compiled: &Compiled) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let temporary = path.with_extension(format!("{}.tmp", process::id()));
+        File::create(&temporary)?.write_all(&compiled.encode(key))?;
+        fs::rename(&temporary, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::Command;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use parsers::ParseOptions;
+    use {l, link, load};
+
+    fn compiled() -> Compiled {
+        let mut labels = HashMap::new();
+        labels.insert(l("01"), 0x40);
+        labels.insert(Label::Translated(3), 0x80);
+        Compiled {
+            code: vec![0x55, 0xc3],
+            labels,
+            trace_names: vec!["push 1".to_string(), "exit".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let compiled = compiled();
+        let data = compiled.encode(7);
+        assert_eq!(Compiled::decode(&data, 7), Some(compiled));
+        assert_eq!(Compiled::decode(&data, 8), None);
+        assert_eq!(Compiled::decode(&data[..data.len() - 1], 7), None);
+        assert_eq!(Compiled::decode(&[data.clone(), vec![0]].concat(), 7), None);
+        assert_eq!(Compiled::decode(b"", 7), None);
+    }
+
+    #[test]
+    fn test_key() {
+        let options = Options::parse(vec!["a.ws".to_string()]).unwrap();
+        let optimized = Options { opt_level: 1, ..options.clone() };
+        let strict = Options {
+            parse: ParseOptions {
+                strict: true,
+                ..options.parse.clone()
+            },
+            ..options.clone()
+        };
+        assert_eq!(key(b"   \n", &options), key(b"   \n", &options));
+        assert_ne!(key(b"   \n", &options), key(b"  \t\n", &options));
+        assert_ne!(key(b"   \n", &options), key(b"   \n", &optimized));
+        assert_ne!(key(b"   \n", &options), key(b"   \n", &strict));
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = env::temp_dir().join(format!("whitespace-cache-test-{}", process::id()));
+        let cache = Cache::new(dir.clone());
+        assert_eq!(cache.load(1), None);
+        cache.store(1, &compiled()).unwrap();
+        assert_eq!(cache.load(1), Some(compiled()));
+        assert_eq!(cache.load(2), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_runs_from_cache() {
+        // Counts down from 3, then prints it again from a subroutine.
+        let program = vec![Command::Push(3),
+                           Command::Mark(l("0")),
+                           Command::Duplicate,
+                           Command::OutputNum,
+                           Command::Push(1),
+                           Command::Subtract,
+                           Command::Duplicate,
+                           Command::JumpZero(l("1")),
+                           Command::Jump(l("0")),
+                           Command::Mark(l("1")),
+                           Command::Call(l("10")),
+                           Command::Exit,
+                           Command::Mark(l("10")),
+                           Command::Push(3),
+                           Command::OutputNum,
+                           Command::Return];
+        let mut context = Context::new();
+        let (code, _) = link(program, &mut context, false);
+        let compiled = Compiled::new(code, &context);
+        let decoded = Compiled::decode(&compiled.encode(0), 0).unwrap();
+
+        // Loaded at some other address, against a context which never saw
+        // the program compiled.
+        let run = |compiled: &Compiled| {
+            let mut context = Context::new();
+            let stdout = Rc::new(RefCell::new(Vec::new()));
+            context.capture_stdout(stdout.clone());
+            compiled.install(&mut context);
+            load(&compiled.code).unwrap().execute(&mut context);
+            let stdout = stdout.borrow().clone();
+            (stdout, context.stack, context.heap.to_map(), context.labels)
+        };
+        assert_eq!(run(&decoded), run(&compiled));
+        assert_eq!(run(&decoded).3, context.labels);
+    }
+}
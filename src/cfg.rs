@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+
+use command::Command;
+use Label;
+
+pub type BlockId = usize;
+
+/// Why control can go from one block to another.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Edge {
+    /// A jump, or a conditional jump when the condition holds.
+    Taken,
+    /// Running off the end of the block into the next one. After a call,
+    /// this is where the subroutine returns to.
+    Fallthrough,
+    /// A call to a subroutine.
+    Call,
+}
+
+/// A straight line of commands: only the first can be jumped to, and only the
+/// last can jump anywhere.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Block {
+    /// Indices of the commands in this block.
+    pub range: Range<usize>,
+    /// The label marked at the start of the block, if any.
+    pub label: Option<Label>,
+}
+
+/// The control-flow graph of a program. Block 0 is the entry point.
+pub struct Cfg<'a> {
+    pub program: &'a [Command],
+    pub blocks: Vec<Block>,
+    pub successors: Vec<Vec<(BlockId, Edge)>>,
+    pub predecessors: Vec<Vec<(BlockId, Edge)>>,
+    by_label: HashMap<Label, BlockId>,
+}
+
+/// Whether a block has to end after this command.
+fn ends_block(command: &Command) -> bool {
+    matches!(*command,
+             Command::Jump(_) |
+             Command::JumpZero(_) |
+             Command::JumpNegative(_) |
+             Command::Call(_) |
+             Command::Return |
+             Command::Exit)
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(program: &'a [Command]) -> Self {
+        let mut blocks = vec![];
+        let mut start = 0;
+        for (i, command) in program.iter().enumerate() {
+            if let Command::Mark(_) = *command {
+                if start < i {
+                    blocks.push(start..i);
+                }
+                start = i;
+            }
+            if ends_block(command) {
+                blocks.push(start..i + 1);
+                start = i + 1;
+            }
+        }
+        if start < program.len() {
+            blocks.push(start..program.len());
+        }
+
+        let blocks = blocks.into_iter()
+                           .map(|range| {
+                               let label = match program[range.start] {
+                                   Command::Mark(ref l) => Some(l.clone()),
+                                   _ => None,
+                               };
+                               Block { range, label }
+                           })
+                           .collect::<Vec<Block>>();
+
+        // If a label is marked twice, jumps go to the first one.
+        let mut by_label = HashMap::new();
+        for (id, block) in blocks.iter().enumerate().rev() {
+            if let Some(ref label) = block.label {
+                by_label.insert(label.clone(), id);
+            }
+        }
+
+        let mut cfg = Cfg {
+            program,
+            successors: vec![vec![]; blocks.len()],
+            predecessors: vec![vec![]; blocks.len()],
+            blocks,
+            by_label,
+        };
+        for id in 0..cfg.blocks.len() {
+            let next = if id + 1 < cfg.blocks.len() { Some(id + 1) } else { None };
+            let mut edges = vec![];
+            match *cfg.last_command(id) {
+                Command::Jump(ref l) => edges.extend(cfg.block_of(l).map(|b| (b, Edge::Taken))),
+                Command::JumpZero(ref l) |
+                Command::JumpNegative(ref l) => {
+                    edges.extend(cfg.block_of(l).map(|b| (b, Edge::Taken)));
+                    edges.extend(next.map(|b| (b, Edge::Fallthrough)));
+                }
+                Command::Call(ref l) => {
+                    edges.extend(cfg.block_of(l).map(|b| (b, Edge::Call)));
+                    edges.extend(next.map(|b| (b, Edge::Fallthrough)));
+                }
+                Command::Return | Command::Exit => {}
+                _ => edges.extend(next.map(|b| (b, Edge::Fallthrough))),
+            }
+            for &(to, edge) in &edges {
+                cfg.predecessors[to].push((id, edge));
+            }
+            cfg.successors[id] = edges;
+        }
+        cfg
+    }
+
+    /// The block which starts with the given label.
+    pub fn block_of(&self, label: &Label) -> Option<BlockId> {
+        self.by_label.get(label).cloned()
+    }
+
+    /// The commands in a block.
+    pub fn commands(&self, id: BlockId) -> &'a [Command] {
+        &self.program[self.blocks[id].range.clone()]
+    }
+
+    fn last_command(&self, id: BlockId) -> &'a Command {
+        &self.program[self.blocks[id].range.end - 1]
+    }
+
+    /// Blocks reachable from `start`, in reverse postorder, only following
+    /// the given kinds of edge.
+    pub fn reverse_postorder(&self, start: BlockId, follow: &[Edge]) -> Vec<BlockId> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut order = vec![];
+        // Each entry is a block and how many of its successors we've visited.
+        let mut stack = vec![(start, 0)];
+        seen[start] = true;
+        while let Some(&mut (id, ref mut next)) = stack.last_mut() {
+            if let Some(&(to, edge)) = self.successors[id].get(*next) {
+                *next += 1;
+                if follow.contains(&edge) && !seen[to] {
+                    seen[to] = true;
+                    stack.push((to, 0));
+                }
+            } else {
+                order.push(id);
+                stack.pop();
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Blocks reachable from the entry point.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        if !self.blocks.is_empty() {
+            for id in self.reverse_postorder(0, &[Edge::Taken, Edge::Fallthrough, Edge::Call]) {
+                reachable[id] = true;
+            }
+        }
+        reachable
+    }
+
+    /// The immediate dominator of each block reachable from the entry point,
+    /// following every kind of edge. The entry point is its own dominator.
+    ///
+    /// This is "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and
+    /// Kennedy.
+    pub fn dominators(&self) -> Vec<Option<BlockId>> {
+        let mut idom = vec![None; self.blocks.len()];
+        if self.blocks.is_empty() {
+            return idom;
+        }
+
+        let order = self.reverse_postorder(0, &[Edge::Taken, Edge::Fallthrough, Edge::Call]);
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, &id) in order.iter().enumerate() {
+            position[id] = i;
+        }
+
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &id in &order[1..] {
+                let mut new_idom = None;
+                for &(pred, _) in &self.predecessors[id] {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => {
+                            // Walk up the tree from both until they meet.
+                            let (mut a, mut b) = (pred, other);
+                            while a != b {
+                                while position[a] > position[b] {
+                                    a = idom[a].unwrap();
+                                }
+                                while position[b] > position[a] {
+                                    b = idom[b].unwrap();
+                                }
+                            }
+                            a
+                        }
+                    });
+                }
+                if new_idom != idom[id] {
+                    idom[id] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    /// Whether every path from the entry point to `b` goes through `a`.
+    pub fn dominates(&self, idom: &[Option<BlockId>], a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(up) if up != b => b = up,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Which subroutines each subroutine calls, starting from the entry
+    /// point. Subroutines are identified by their first block.
+    pub fn call_graph(&self) -> CallGraph {
+        let mut graph = CallGraph {
+            entry: 0,
+            calls: BTreeMap::new(),
+        };
+        if self.blocks.is_empty() {
+            return graph;
+        }
+
+        let mut todo = vec![0];
+        while let Some(subroutine) = todo.pop() {
+            if graph.calls.contains_key(&subroutine) {
+                continue;
+            }
+            let mut callees = BTreeSet::new();
+            for id in self.reverse_postorder(subroutine, &[Edge::Taken, Edge::Fallthrough]) {
+                for &(to, edge) in &self.successors[id] {
+                    if edge == Edge::Call {
+                        callees.insert(to);
+                    }
+                }
+            }
+            todo.extend(callees.iter().cloned());
+            graph.calls.insert(subroutine, callees);
+        }
+        graph
+    }
+}
+
+/// Which subroutines call which, by the first block of each subroutine.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CallGraph {
+    pub entry: BlockId,
+    pub calls: BTreeMap<BlockId, BTreeSet<BlockId>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use l;
+
+    /// A loop calling a subroutine, which calls another.
+    fn program() -> Vec<Command> {
+        vec![Command::Push(3),                  // 0: block 0
+             Command::Mark(l("0")),             // 1: block 1
+             Command::Call(l("1")),             // 2
+             Command::Push(1),                  // 3: block 2
+             Command::Subtract,                 // 4
+             Command::Duplicate,                // 5
+             Command::JumpZero(l("01")),        // 6
+             Command::Jump(l("0")),             // 7: block 3
+             Command::Mark(l("01")),            // 8: block 4
+             Command::Exit,                     // 9
+             Command::Mark(l("1")),             // 10: block 5
+             Command::Call(l("11")),            // 11
+             Command::Return,                   // 12: block 6
+             Command::Mark(l("11")),            // 13: block 7
+             Command::OutputNum,                // 14
+             Command::Return]                   // 15
+    }
+
+    #[test]
+    fn test_blocks() {
+        let program = program();
+        let cfg = Cfg::new(&program);
+        let ranges = cfg.blocks.iter().map(|b| b.range.clone()).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![0..1, 1..3, 3..7, 7..8, 8..10, 10..12, 12..13, 13..16]);
+        assert_eq!(cfg.blocks[1].label, Some(l("0")));
+        assert_eq!(cfg.blocks[2].label, None);
+        assert_eq!(cfg.block_of(&l("11")), Some(7));
+    }
+
+    #[test]
+    fn test_edges() {
+        let program = program();
+        let cfg = Cfg::new(&program);
+        assert_eq!(cfg.successors[0], vec![(1, Edge::Fallthrough)]);
+        assert_eq!(cfg.successors[1], vec![(5, Edge::Call), (2, Edge::Fallthrough)]);
+        assert_eq!(cfg.successors[2], vec![(4, Edge::Taken), (3, Edge::Fallthrough)]);
+        assert_eq!(cfg.successors[3], vec![(1, Edge::Taken)]);
+        assert_eq!(cfg.successors[4], vec![]);
+        assert_eq!(cfg.successors[7], vec![]);
+        assert_eq!(cfg.predecessors[1], vec![(0, Edge::Fallthrough), (3, Edge::Taken)]);
+    }
+
+    #[test]
+    fn test_dominators() {
+        let program = program();
+        let cfg = Cfg::new(&program);
+        let idom = cfg.dominators();
+        assert_eq!(idom,
+                   vec![Some(0), Some(0), Some(1), Some(2), Some(2), Some(1), Some(5), Some(5)]);
+        assert!(cfg.dominates(&idom, 1, 4));
+        assert!(!cfg.dominates(&idom, 3, 4));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let program = vec![Command::Exit, Command::Push(1)];
+        let cfg = Cfg::new(&program);
+        assert_eq!(cfg.reachable(), vec![true, false]);
+        assert_eq!(cfg.dominators(), vec![Some(0), None]);
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let program = program();
+        let cfg = Cfg::new(&program);
+        let graph = cfg.call_graph();
+        assert_eq!(graph.entry, 0);
+        let calls = graph.calls
+                         .iter()
+                         .map(|(&from, to)| (from, to.iter().cloned().collect()))
+                         .collect::<Vec<(BlockId, Vec<BlockId>)>>();
+        assert_eq!(calls, vec![(0, vec![5]), (5, vec![7]), (7, vec![])]);
+    }
+}
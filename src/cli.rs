@@ -0,0 +1,296 @@
+use optimize;
+use parsers::ParseOptions;
+
+pub const USAGE: &str = "\
+usage: whitespace [run] [options] <file> [-O<level>] [--dump-ir] [--dump-asm] [--cache]
+                                         [--harden] [--catch-faults] [--perf-map] [--gdb]
+       whitespace check [options] <file>
+       whitespace disassemble [options] <file>
+       whitespace features [options] <file>
+       whitespace graph [options] <file> [--cfg | --callgraph] [-o <output>]
+       whitespace minify [options] <file> [-o <output>]
+       whitespace obfuscate [options] <file> [-o <output>] [--seed <n>] [--stdin <file>]
+
+options:
+       --strict          reject extension instructions
+       --dialect <ver>   reject instructions newer than Whitespace 0.2 or 0.3";
+
+/// What we were asked to do with the input file.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Subcommand {
+    Run,
+    Check,
+    Disassemble,
+    Features,
+    Graph,
+    Minify,
+    Obfuscate,
+}
+
+/// Which graph to draw.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Graph {
+    /// Basic blocks and the jumps between them.
+    Cfg,
+    /// Which subroutines call which.
+    CallGraph,
+}
+
+/// Options given on the command line.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Options {
+    pub subcommand: Subcommand,
+    pub input: String,
+    pub output: Option<String>,
+    /// Seed for the obfuscator's random choices.
+    pub seed: Option<u64>,
+    /// File to use as stdin when checking an obfuscated program.
+    pub stdin: Option<String>,
+    pub graph: Graph,
+    /// How hard to optimize before running.
+    pub opt_level: u8,
+    /// Show the program before and after optimizing.
+    pub dump_ir: bool,
+    /// Show the generated machine code.
+    pub dump_asm: bool,
+    /// Use compiled code from earlier runs, and keep this run's for later. Only
+    /// for use where nobody else can write to the cache.
+    pub cache: bool,
+    /// Compile so that the program can't choose any of the machine code, for
+    /// running programs which aren't trusted.
+    pub harden: bool,
+    /// Report faults in the generated code, rather than crashing.
+    pub catch_faults: bool,
+    /// Tell `perf` which code is which, in `/tmp/perf-<pid>.map`.
+    pub perf_map: bool,
+    /// Tell GDB about the generated code, so it can name and unwind it.
+    pub gdb: bool,
+    pub parse: ParseOptions,
+}
+
+impl Options {
+    /// Parses the command line arguments, not including the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut args = args.into_iter().peekable();
+
+        let subcommand = match args.peek().map(|s| &s[..]) {
+            Some("run") => Subcommand::Run,
+            Some("check") => Subcommand::Check,
+            Some("disassemble") => Subcommand::Disassemble,
+            Some("features") => Subcommand::Features,
+            Some("graph") => Subcommand::Graph,
+            Some("minify") => Subcommand::Minify,
+            Some("obfuscate") => Subcommand::Obfuscate,
+            _ => {
+                // Running is the default, so there's nothing to skip.
+                return Options::parse_rest(Subcommand::Run, args);
+            }
+        };
+        args.next();
+        Options::parse_rest(subcommand, args)
+    }
+
+    fn parse_rest<I: Iterator<Item = String>>(subcommand: Subcommand,
+                                              mut args: I)
+                                              -> Result<Options, String> {
+        let mut input = None;
+        let mut output = None;
+        let mut seed = None;
+        let mut stdin = None;
+        let mut graph = Graph::Cfg;
+        let mut opt_level = 0;
+        let mut dump_ir = false;
+        let mut dump_asm = false;
+        let mut cache = false;
+        let mut harden = false;
+        let mut catch_faults = false;
+        let mut perf_map = false;
+        let mut gdb = false;
+        let mut parse = ParseOptions::default();
+
+        let writes_output = subcommand == Subcommand::Graph ||
+                            subcommand == Subcommand::Minify ||
+                            subcommand == Subcommand::Obfuscate;
+
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "-o" if writes_output => {
+                    output = Some(args.next().ok_or("-o requires an argument")?);
+                }
+                "--seed" if subcommand == Subcommand::Obfuscate => {
+                    let n = args.next().ok_or("--seed requires an argument")?;
+                    seed = Some(n.parse().map_err(|_| format!("invalid seed {}", n))?);
+                }
+                "--stdin" if subcommand == Subcommand::Obfuscate => {
+                    stdin = Some(args.next().ok_or("--stdin requires an argument")?);
+                }
+                "--cfg" if subcommand == Subcommand::Graph => graph = Graph::Cfg,
+                "--callgraph" if subcommand == Subcommand::Graph => graph = Graph::CallGraph,
+                "-O" if subcommand == Subcommand::Run => opt_level = 1,
+                _ if arg.starts_with("-O") && subcommand == Subcommand::Run => {
+                    opt_level = match arg[2..].parse() {
+                        Ok(n) if n <= optimize::MAX_LEVEL => n,
+                        _ => return Err(format!("invalid optimization level {}", &arg[2..])),
+                    };
+                }
+                "--dump-ir" if subcommand == Subcommand::Run => dump_ir = true,
+                "--dump-asm" if subcommand == Subcommand::Run => dump_asm = true,
+                "--cache" if subcommand == Subcommand::Run => cache = true,
+                "--harden" if subcommand == Subcommand::Run => harden = true,
+                "--catch-faults" if subcommand == Subcommand::Run => catch_faults = true,
+                "--perf-map" if subcommand == Subcommand::Run => perf_map = true,
+                "--gdb" if subcommand == Subcommand::Run => gdb = true,
+                "--strict" => parse.strict = true,
+                "--dialect" => {
+                    parse.dialect = args.next().ok_or("--dialect requires an argument")?.parse()?;
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                _ if input.is_none() => input = Some(arg),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        Ok(Options {
+            subcommand,
+            input: input.ok_or("no input file given")?,
+            output,
+            seed,
+            stdin,
+            graph,
+            opt_level,
+            dump_ir,
+            dump_asm,
+            cache,
+            harden,
+            catch_faults,
+            perf_map,
+            gdb,
+            parse,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dialect::Dialect;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_default_subcommand() {
+        let options = parse(&["hello.ws"]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Run);
+        assert_eq!(options.input, "hello.ws");
+    }
+
+    #[test]
+    fn test_minify() {
+        let options = parse(&["minify", "hello.ws", "-o", "small.ws"]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Minify);
+        assert_eq!(options.input, "hello.ws");
+        assert_eq!(options.output, Some("small.ws".to_string()));
+    }
+
+    #[test]
+    fn test_obfuscate() {
+        let options = parse(&["obfuscate", "a.ws", "--seed", "42", "--stdin", "in.txt"]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Obfuscate);
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.stdin, Some("in.txt".to_string()));
+
+        assert!(parse(&["obfuscate", "a.ws", "--seed", "x"]).is_err());
+        assert!(parse(&["minify", "a.ws", "--seed", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_graph() {
+        let options = parse(&["graph", "a.ws", "-o", "a.dot"]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Graph);
+        assert_eq!(options.graph, Graph::Cfg);
+        assert_eq!(options.output, Some("a.dot".to_string()));
+
+        assert_eq!(parse(&["graph", "--callgraph", "a.ws"]).unwrap().graph, Graph::CallGraph);
+        assert!(parse(&["run", "--cfg", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(parse(&["a.ws"]).unwrap().opt_level, 0);
+        assert_eq!(parse(&["-O", "a.ws"]).unwrap().opt_level, 1);
+        let options = parse(&["run", "-O0", "--dump-ir", "a.ws"]).unwrap();
+        assert_eq!(options.opt_level, 0);
+        assert!(options.dump_ir);
+
+        assert!(parse(&["-O9", "a.ws"]).is_err());
+        assert!(parse(&["-Ox", "a.ws"]).is_err());
+        assert!(parse(&["minify", "-O1", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_dump_asm() {
+        assert!(!parse(&["a.ws"]).unwrap().dump_asm);
+        assert!(parse(&["run", "a.ws", "--dump-asm"]).unwrap().dump_asm);
+        assert!(parse(&["check", "--dump-asm", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        assert!(!parse(&["a.ws"]).unwrap().cache);
+        assert!(parse(&["run", "--cache", "a.ws"]).unwrap().cache);
+        assert!(parse(&["minify", "--cache", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_harden() {
+        assert!(!parse(&["a.ws"]).unwrap().harden);
+        assert!(parse(&["run", "a.ws", "--harden"]).unwrap().harden);
+        assert!(parse(&["check", "--harden", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_catch_faults() {
+        assert!(!parse(&["a.ws"]).unwrap().catch_faults);
+        assert!(parse(&["run", "a.ws", "--catch-faults"]).unwrap().catch_faults);
+        assert!(parse(&["check", "--catch-faults", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_perf_map() {
+        assert!(!parse(&["a.ws"]).unwrap().perf_map);
+        assert!(parse(&["run", "--perf-map", "a.ws"]).unwrap().perf_map);
+        assert!(parse(&["graph", "--perf-map", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_gdb() {
+        assert!(!parse(&["a.ws"]).unwrap().gdb);
+        assert!(parse(&["run", "a.ws", "--gdb"]).unwrap().gdb);
+        assert!(parse(&["minify", "--gdb", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_dialect() {
+        assert_eq!(parse(&["a.ws"]).unwrap().parse.dialect, Dialect::V0_3);
+        let options = parse(&["features", "--dialect", "0.2", "a.ws"]).unwrap();
+        assert_eq!(options.subcommand, Subcommand::Features);
+        assert_eq!(options.parse.dialect, Dialect::V0_2);
+        assert!(parse(&["--dialect", "1.0", "a.ws"]).is_err());
+    }
+
+    #[test]
+    fn test_strict() {
+        assert!(!parse(&["a.ws"]).unwrap().parse.strict);
+        assert!(parse(&["disassemble", "--strict", "a.ws"]).unwrap().parse.strict);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["minify"]).is_err());
+        assert!(parse(&["run", "a.ws", "b.ws"]).is_err());
+        assert!(parse(&["run", "a.ws", "-o", "b.ws"]).is_err());
+    }
+}
@@ -1,7 +1,25 @@
 
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+
+use dialect::Dialect::{V0_2, V0_3};
+use opcodes::Count::{Fixed, PlusArg};
+#[cfg(feature = "extensions")]
+use opcodes::Origin::Extension;
+use opcodes::Origin::Standard;
+use asm::{Assembler, Cond, Mem, Reg};
+use asm::Reg::*;
+use frame::{self, HELPERS};
+use heap;
+use helpers::Helper;
+use lower::Lowering;
+use opcodes::Operand;
+use stack;
 use {Label, Number};
 use wsstd::Context;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum IMP {
     Stack,
@@ -11,196 +29,655 @@ pub enum IMP {
     IO,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
-pub enum Command {
-    // Meta commands
-    Initialize,
-    Deinitialize,
+impl IMP {
+    /// The characters which introduce instructions of this kind.
+    pub fn code(&self) -> &'static [u8] {
+        match *self {
+            IMP::Stack => b" ",
+            IMP::Arithmetic => b"\t ",
+            IMP::Heap => b"\t\t",
+            IMP::Flow => b"\n",
+            IMP::IO => b"\t\n",
+        }
+    }
+}
+
+/// Every IMP, in no particular order.
+pub const IMPS: &[IMP] = &[IMP::Stack, IMP::Arithmetic, IMP::Heap, IMP::Flow, IMP::IO];
+
+// Note that store, retrieve and the IO commands leave their arguments on the
+// stack in this implementation.
+opcodes! {
+    meta { Initialize, Deinitialize }
 
     // Stack commands
-    Push(Number),
-    Duplicate,
-    Copy(Number),
-    Swap,
-    Pop,
-    Slide(Number),
+    Push(n: Number)         => Standard(V0_2), Stack,      b" ",    "push",       Fixed(0),   Fixed(1);
+    Duplicate               => Standard(V0_2), Stack,      b"\n ",  "dup",        Fixed(1),   Fixed(2);
+    Copy(n: Number)         => Standard(V0_3), Stack,      b"\t ",  "copy",       PlusArg(1), PlusArg(2);
+    Swap                    => Standard(V0_2), Stack,      b"\n\t", "swap",       Fixed(2),   Fixed(2);
+    Pop                     => Standard(V0_2), Stack,      b"\n\n", "pop",        Fixed(1),   Fixed(0);
+    Slide(n: Number)        => Standard(V0_3), Stack,      b"\t\n", "slide",      PlusArg(1), Fixed(1);
 
     // Arithmetic commands
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Modulus,
+    Add                     => Standard(V0_2), Arithmetic, b"  ",   "add",        Fixed(2),   Fixed(1);
+    Subtract                => Standard(V0_2), Arithmetic, b" \t",  "sub",        Fixed(2),   Fixed(1);
+    Multiply                => Standard(V0_2), Arithmetic, b" \n",  "mul",        Fixed(2),   Fixed(1);
+    Divide                  => Standard(V0_2), Arithmetic, b"\t ",  "div",        Fixed(2),   Fixed(1);
+    Modulus                 => Standard(V0_2), Arithmetic, b"\t\t", "mod",        Fixed(2),   Fixed(1);
 
     // Heap commands
-    Store,
-    Retrieve,
+    Store                   => Standard(V0_2), Heap,       b" ",    "store",      Fixed(2),   Fixed(2);
+    Retrieve                => Standard(V0_2), Heap,       b"\t",   "retrieve",   Fixed(1),   Fixed(2);
 
     // Flow control commands
-    Mark(Label),
-    Call(Label),
-    Jump(Label),
-    JumpZero(Label),
-    JumpNegative(Label),
-    Return,
-    Exit,
+    Mark(l: Label)          => Standard(V0_2), Flow,       b"  ",   "mark",       Fixed(0),   Fixed(0);
+    Call(l: Label)          => Standard(V0_2), Flow,       b" \t",  "call",       Fixed(0),   Fixed(0);
+    Jump(l: Label)          => Standard(V0_2), Flow,       b" \n",  "jump",       Fixed(0),   Fixed(0);
+    JumpZero(l: Label)      => Standard(V0_2), Flow,       b"\t ",  "jz",         Fixed(1),   Fixed(0);
+    JumpNegative(l: Label)  => Standard(V0_2), Flow,       b"\t\t", "jn",         Fixed(1),   Fixed(0);
+    Return                  => Standard(V0_2), Flow,       b"\t\n", "ret",        Fixed(0),   Fixed(0);
+    Exit                    => Standard(V0_2), Flow,       b"\n\n", "exit",       Fixed(0),   Fixed(0);
 
     // IO commands
-    OutputChar,
-    OutputNum,
-    ReadChar,
-    ReadNum,
-}
-
-const RCX: u8 = 0xb9;
-const RDI: u8 = 0xbf;
-const RSI: u8 = 0xbe;
-/// little-endian move
-macro_rules! mov_le {
-    ($x:ident <- $y:expr) => {
-        {
-            let n: u64 = $y;
-            vec![
-                0x48,
-                $x,
-                ((n >> 0x00) as u8),
-                ((n >> 0x08) as u8),
-                ((n >> 0x10) as u8),
-                ((n >> 0x18) as u8),
-                ((n >> 0x20) as u8),
-                ((n >> 0x28) as u8),
-                ((n >> 0x30) as u8),
-                ((n >> 0x38) as u8),
-            ]
+    OutputChar              => Standard(V0_2), IO,         b"  ",   "outchar",    Fixed(1),   Fixed(1);
+    OutputNum               => Standard(V0_2), IO,         b" \t",  "outnum",     Fixed(1),   Fixed(1);
+    ReadChar                => Standard(V0_2), IO,         b"\t ",  "readchar",   Fixed(1),   Fixed(1);
+    ReadNum                 => Standard(V0_2), IO,         b"\t\t", "readnum",    Fixed(1),   Fixed(1);
+
+    // Debugging extensions, using sequences which are otherwise invalid
+    #[cfg(feature = "extensions")]
+    DumpStack               => Extension,       Flow,       b"\n  ", "dumpstack",  Fixed(0),   Fixed(0);
+    #[cfg(feature = "extensions")]
+    DumpHeap                => Extension,       Flow,       b"\n \t", "dumpheap",   Fixed(0),   Fixed(0);
+    #[cfg(feature = "extensions")]
+    Breakpoint              => Extension,       Flow,       b"\n \n", "breakpoint", Fixed(0),   Fixed(0);
+    #[cfg(feature = "extensions")]
+    TraceOn                 => Extension,       Flow,       b"\n\t ", "traceon",    Fixed(0),   Fixed(0);
+    #[cfg(feature = "extensions")]
+    TraceOff                => Extension,       Flow,       b"\n\t\t", "traceoff",   Fixed(0),   Fixed(0);
+}
+
+/// Encodes a number as a sign followed by the magnitude in binary, without
+/// any redundant leading zeros.
+pub fn encode_number(n: Number) -> Vec<u8> {
+    let mut out = vec![if n < 0 { b'\t' } else { b' ' }];
+    let magnitude = n.unsigned_abs();
+    let width = 64 - magnitude.leading_zeros();
+    for bit in (0..width).rev() {
+        out.push(if magnitude & (1 << bit) != 0 { b'\t' } else { b' ' });
+    }
+    out.push(b'\n');
+    out
+}
+
+/// Encodes a label as its bits, terminated by a newline.
+pub fn encode_label(label: &Label) -> Vec<u8> {
+    match *label {
+        Label::Name(ref bits) => {
+            let mut out = bits.iter()
+                              .map(|&bit| if bit { b'\t' } else { b' ' })
+                              .collect::<Vec<u8>>();
+            out.push(b'\n');
+            out
         }
+        Label::Translated(addr) => panic!("cannot encode translated label {}", addr),
     }
 }
 
+/// Encodes an entire program back into whitespace.
+pub fn encode_program(program: &[Command]) -> Vec<u8> {
+    program.iter()
+           .map(Command::encode)
+           .collect::<Vec<Vec<u8>>>()
+           .concat()
+}
+
+impl Command {
+    /// Converts this command back into whitespace. Meta commands have no
+    /// source representation, so they encode to nothing.
+    pub fn encode(&self) -> Vec<u8> {
+        let opcode = match self.opcode() {
+            Some(opcode) => opcode,
+            None => return vec![],
+        };
+        let arg = match self.operand() {
+            Operand::None => vec![],
+            Operand::Number(n) => encode_number(n),
+            Operand::Label(ref l) => encode_label(l),
+        };
+        [opcode.encoding(), arg].concat()
+    }
+
+    /// How many items this command needs on the stack, and how many it
+    /// leaves in their place. Meta commands don't touch the stack.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match (self.opcode(), self.operand()) {
+            (None, _) => (0, 0),
+            (Some(opcode), Operand::Number(n)) => {
+                (opcode.effect.consumes.resolve(n), opcode.effect.produces.resolve(n))
+            }
+            (Some(opcode), _) => {
+                (opcode.effect.consumes.resolve(0), opcode.effect.produces.resolve(0))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    /// Disassembles this command.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let opcode = match self.opcode() {
+            Some(opcode) => opcode,
+            None => return write!(f, "; {:?}", self),
+        };
+        match self.operand() {
+            Operand::None => write!(f, "{}", opcode.mnemonic),
+            Operand::Number(n) => write!(f, "{} {}", opcode.mnemonic, n),
+            Operand::Label(l) => write!(f, "{} {}", opcode.mnemonic, l),
+        }
+    }
+}
+
+/// Disassembles a program, one command per line. Everything but marks is
+/// indented so the labels stand out, and annotated with its stack effect.
+pub fn disassemble(program: &[Command]) -> String {
+    let mut out = String::new();
+    for command in program {
+        if let Command::Mark(_) = *command {
+            out.push_str(&format!("{}\n", command));
+        } else {
+            let (consumes, produces) = command.stack_effect();
+            out.push_str(&format!("    {:<24} ; {} -> {}\n",
+                                  command.to_string(),
+                                  consumes,
+                                  produces));
+        }
+    }
+    out
+}
+
+/// Where the generated code keeps the context it was called with, for the
+/// whole run. It's callee-saved, so it survives calls into the context.
+pub const CONTEXT: Reg = R14;
+
+/// A field of the context, as a memory operand.
+macro_rules! field {
+    ($($field:tt)+) => {
+        $crate::asm::Mem::new($crate::command::CONTEXT,
+                              ::std::mem::offset_of!($crate::wsstd::Context, $($field)+) as i32)
+    }
+}
+
+/// Calls one of the helpers, with `rsi` holding the argument, if there is
+/// one. See `call_helper`.
 macro_rules! fn_call {
-    ($x:ident : $y:expr, RSI: $z:expr) => {
-        vec![
-            mov_le!(RDI <- $y as *const _ as u64),
-            mov_le!(RSI <- $z),
-            mov_le!(RCX <- $crate::wsstd::Context::$x as u64),
-            // call rcx
-            vec![0xff, 0xd1],
-        ].concat()
-    };
-    ($x:ident : $y:expr, RSI_setter: $z:expr) => {
-        vec![
-            mov_le!(RDI <- $y as *const _ as u64),
-            $z,
-            mov_le!(RCX <- $crate::wsstd::Context::$x as u64),
-            // call rcx
-            vec![0xff, 0xd1],
-        ].concat()
-    };
-    ($x:ident : $y:expr) => {
-        vec![
-            mov_le!(RDI <- $y as *const _ as u64),
-            mov_le!(RCX <- $crate::wsstd::Context::$x as u64),
-            // call rcx
-            vec![0xff, 0xd1],
-        ].concat()
+    ($a:expr, $x:ident, RSI: $z:expr) => {{
+        $a.mov_const($crate::asm::Reg::Rsi, $z as u64);
+        fn_call!($a, $x)
+    }};
+    ($a:expr, $x:ident) => {
+        $crate::command::call_helper($a, $crate::helpers::Helper::$x)
     };
 }
 
-macro_rules! arith {
-    ($c:expr, $x:expr) => {
-        vec![
-            fn_call!(pop_stack: $c),
-            // mov r12, rax
-            vec![0x49, 0x89, 0xc4],
-            fn_call!(pop_stack: $c),
-            $x,
-            fn_call!(push_stack: $c, RSI_setter: vec![0x48, 0x89, 0xc6]),
-                                                     // mov rsi, rax
-        ].concat()
+/// Calls a helper with `rdi` pointing at the context. Each call is just a
+/// `call rel32` to a stub for that helper, which `stubs` puts after the rest
+/// of the code and which does the rest.
+pub fn call_helper(a: &mut Assembler, helper: Helper) {
+    if a.inline_helpers() {
+        helper_call(a, helper, Assembler::call_mem);
+    } else {
+        let stub = a.stub(helper);
+        a.call(stub);
+    }
+}
+
+/// Loads the arguments and goes to a helper, with `jump` as either a call or
+/// a jump through the table.
+fn helper_call(a: &mut Assembler, helper: Helper, jump: fn(&mut Assembler, Mem)) {
+    a.mov(Rdi, CONTEXT);
+    a.load(Rax, HELPERS);
+    jump(a, Mem::new(Rax, helper.offset()));
+}
+
+/// Emits a stub for each helper the code calls. Each jumps on to its helper,
+/// which returns straight to where the stub was called from.
+pub fn stubs(a: &mut Assembler) {
+    for (helper, label) in a.stubs() {
+        a.bind(label);
+        helper_call(a, helper, Assembler::jmp_mem);
+    }
+}
+
+/// Counts a step, and leaves the program if it's out of them. A step is
+/// entering a basic block, so anything which runs forever keeps taking them,
+/// and a program takes about as many however its blocks are laid out.
+fn step(a: &mut Assembler) {
+    a.load(Rax, field!(steps_left));
+    a.sub_imm(Rax, 1);
+    a.store(field!(steps_left), Rax);
+    let exit = a.exit();
+    a.jcc(Cond::S, exit);
+}
+
+/// Pops two items, and pushes the result of an arithmetic command on them.
+fn arithmetic(command: &Command, a: &mut Assembler) {
+    stack::pop(a);
+    a.mov(R12, Rax);
+    stack::pop(a);
+    match *command {
+        Command::Add => a.add(Rax, R12),
+        Command::Subtract => a.sub(Rax, R12),
+        Command::Multiply => a.imul(Rax, R12),
+        Command::Divide => {
+            a.cqo();
+            a.idiv(R12);
+        }
+        Command::Modulus => {
+            a.cqo();
+            a.idiv(R12);
+            a.mov(Rax, Rdx);
+        }
+        _ => unreachable!(),
     }
+    a.mov(Rsi, Rax);
+    stack::push(a);
 }
 
 impl Command {
-    /// Converts this command into assembly.
-    /// TODO handle "linking"
-    pub fn assemble(self, c: &Context) -> Vec<u8> {
+    /// Assembles this command. Jumps and calls go to the labels named after
+    /// the program's marks; see `link`.
+    pub fn assemble(self, a: &mut Assembler) {
         match self {
-            Command::Initialize => vec![
-                // push rbp
-                0x55,
-                // mov rbp, rsp
-                0x48, 0x89, 0xe5,
-
-                // we can use a few registers, but we have to restore them after
-                // push rbx
-                0x53,
-                // push r12
-                0x41, 0x54,
-            ],
-            Command::Deinitialize | Command::Exit => vec![
-                // pop r12
-                0x41, 0x5c,
-                // pop rbx
-                0x5b,
-                // mov rsp, rbp
-                0x48, 0x89, 0xec,
-                // pop rbp
-                0x5d,
-                // ret
-                0xc3
-            ],
-            Command::Push(n) => fn_call!(push_stack: c, RSI: n as u64),
-            Command::Duplicate => vec![
-                fn_call!(peek_stack: c, RSI: 0),
-                fn_call!(push_stack: c, RSI_setter: vec![0x48, 0x89, 0xc6]),
-                                                           // mov rsi, rax
-            ].concat(),
-            Command::Swap => vec![
-                fn_call!(pop_stack: c),
-                // mov rbx, rax
-                vec![0x48, 0x89, 0xc3],
-
-                fn_call!(pop_stack: c),
-                // mov r12, rax ; store returned value elsewhere
-                vec![0x49, 0x89, 0xc4],
-
-                fn_call!(push_stack: c, RSI_setter: vec![0x48, 0x89, 0xde]),
-                                                         // mov rsi, rbx
-
-                fn_call!(push_stack: c, RSI_setter: vec![0x4c, 0x89, 0xe6]),
-                                                         // mov rsi, r12
-            ].concat(),
-            Command::Pop => fn_call!(pop_stack: c),
-            Command::Copy(n) => vec![
-                fn_call!(peek_stack: c, RSI: n as u64),
-                fn_call!(push_stack: c, RSI_setter: vec![0x48, 0x89, 0xc6]),
-                                                         // mov rsi, rax
-            ].concat(),
-            Command::Add => arith!(c, vec![0x4c, 0x01, 0xe0]),
-                                           // add rax, r12
-            Command::Subtract => arith!(c, vec![0x4c, 0x29, 0xe0]),
-                                                // sub rax, r12
-            Command::Multiply => arith!(c, vec![0x49, 0xf7, 0xe4]),
-                                                // mul r12
-            Command::Divide => arith!(c, vec![0x48, 0x99,
-                                              // cqo    ; sign extend rax into rdx:rax
-                                              0x49, 0xf7, 0xfc]),
-                                              // idiv r12
-            Command::Modulus => arith!(c, vec![0x48, 0x99,
-                                               // cqo
-                                               0x49, 0xf7, 0xfc,
-                                               // idiv r12
-                                               0x48, 0x89, 0xd0]),
-                                               // mov rax, rdx
-            Command::OutputChar => fn_call!(print: c, RSI: 1),
-            Command::OutputNum => fn_call!(print: c, RSI: 0),
-            Command::ReadChar => fn_call!(read: c, RSI: 1),
-            Command::ReadNum => fn_call!(read: c, RSI: 0),
-            Command::Store => fn_call!(store: c),
-            Command::Retrieve => vec![
-                fn_call!(retrieve: c),
-                fn_call!(push_stack: c, RSI_setter: vec![0x48, 0x89, 0xc6]),
-            ].concat(),
-            _ => unimplemented!(),
+            Command::Initialize => {
+                frame::enter(a);
+                a.mov(CONTEXT, Rdi);
+                a.store(HELPERS, Rsi);
+                stack::enter(a);
+            }
+            // The only way out of the code, which exiting jumps to.
+            Command::Deinitialize => {
+                let exit = a.exit();
+                a.bind(exit);
+                stack::sync(a);
+                frame::leave(a);
+            }
+            Command::Exit => {
+                let exit = a.exit();
+                a.jmp(exit);
+            }
+            Command::Mark(_) => {}
+            Command::Call(label) => {
+                // keep the stack aligned for calls to the context
+                a.sub_imm(Rsp, 8);
+                let target = a.named(&label);
+                a.call(target);
+            }
+            Command::Jump(label) => {
+                let target = a.named(&label);
+                a.jmp(target);
+            }
+            Command::JumpZero(label) => {
+                stack::pop(a);
+                a.test(Rax, Rax);
+                let target = a.named(&label);
+                a.jcc(Cond::E, target);
+            }
+            Command::JumpNegative(label) => {
+                stack::pop(a);
+                a.test(Rax, Rax);
+                let target = a.named(&label);
+                a.jcc(Cond::S, target);
+            }
+            // also drops the padding pushed by call
+            Command::Return => a.ret_imm(8),
+            Command::Push(n) => {
+                a.mov_const(Rsi, n as u64);
+                stack::push(a);
+            }
+            Command::Duplicate => {
+                stack::peek(a, 0);
+                a.mov(Rsi, Rax);
+                stack::push(a);
+            }
+            Command::Swap => {
+                stack::pop(a);
+                a.mov(Rbx, Rax);
+                stack::pop(a);
+                a.mov(R12, Rax);
+                a.mov(Rsi, Rbx);
+                stack::push(a);
+                a.mov(Rsi, R12);
+                stack::push(a);
+            }
+            Command::Pop => stack::pop(a),
+            Command::Slide(n) => {
+                stack::sync(a);
+                fn_call!(a, SlideStack, RSI: n);
+                stack::enter(a);
+            }
+            Command::Copy(n) => {
+                stack::peek(a, n);
+                a.mov(Rsi, Rax);
+                stack::push(a);
+            }
+            Command::Add | Command::Subtract | Command::Multiply | Command::Divide |
+            Command::Modulus => arithmetic(&self, a),
+            // These only read the stack, so it can't have moved.
+            Command::OutputChar => {
+                stack::sync(a);
+                fn_call!(a, Print, RSI: 1);
+            }
+            Command::OutputNum => {
+                stack::sync(a);
+                fn_call!(a, Print, RSI: 0);
+            }
+            Command::ReadChar => {
+                stack::sync(a);
+                fn_call!(a, Read, RSI: 1);
+            }
+            Command::ReadNum => {
+                stack::sync(a);
+                fn_call!(a, Read, RSI: 0);
+            }
+            Command::Store => heap::store(a),
+            Command::Retrieve => {
+                heap::retrieve(a);
+                a.mov(Rsi, Rax);
+                stack::push(a);
+            }
+            #[cfg(feature = "extensions")]
+            Command::DumpStack => {
+                stack::sync(a);
+                fn_call!(a, DumpStack);
+            }
+            #[cfg(feature = "extensions")]
+            Command::DumpHeap => fn_call!(a, DumpHeap),
+            #[cfg(feature = "extensions")]
+            Command::Breakpoint => {
+                stack::sync(a);
+                fn_call!(a, Breakpoint);
+            }
+            #[cfg(feature = "extensions")]
+            Command::TraceOn => fn_call!(a, SetTrace, RSI: 1),
+            #[cfg(feature = "extensions")]
+            Command::TraceOff => fn_call!(a, SetTrace, RSI: 0),
         }
     }
 }
+
+/// Whether the program might turn on tracing, in which case every command
+/// needs to report itself to the context. Also tells the context what to
+/// call each command.
+#[cfg(feature = "extensions")]
+fn prepare_tracing(program: &[Command], context: &mut Context) -> bool {
+    if program.contains(&Command::TraceOn) {
+        context.trace_names = program.iter().map(Command::to_string).collect();
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(not(feature = "extensions"))]
+fn prepare_tracing(_: &[Command], _: &mut Context) -> bool {
+    false
+}
+
+/// Reports to the context that the command at `index` is about to run.
+#[cfg(feature = "extensions")]
+fn trace(a: &mut Assembler, index: usize) {
+    stack::sync(a);
+    fn_call!(a, Trace, RSI: index);
+}
+
+#[cfg(not(feature = "extensions"))]
+fn trace(_: &mut Assembler, _: usize) {}
+
+/// Which code came from each command of a program.
+pub type SourceMap = Vec<(Command, Range<usize>)>;
+
+/// Links a program with an assembler set up the usual way.
+#[cfg(test)]
+pub fn link(program: Vec<Command>, context: &mut Context) -> Result<(Vec<u8>, SourceMap), Label> {
+    link_with(program, context, Assembler::new())
+}
+
+/// Assembles a whole program with `a`, then resolves the jumps and calls in
+/// it. The address of each label is recorded in the context. Fails with the
+/// first label that's used but never marked.
+///
+/// Also says which code came from each command. Commands which are held back
+/// in registers have no code of their own; it turns up with whichever command
+/// next needs the stack.
+pub fn link_with(program: Vec<Command>,
+             context: &mut Context,
+             mut a: Assembler)
+             -> Result<(Vec<u8>, SourceMap), Label> {
+    let mut map = Vec::with_capacity(program.len());
+    let mut marked = HashSet::new();
+
+    let traced = prepare_tracing(&program, context);
+    // Tracing shows the stack before every command, so nothing can be held
+    // back in registers.
+    let mut lowering = Lowering::new(!traced);
+    for (i, command) in program.into_iter().enumerate() {
+        let start = a.len();
+        if traced && command.opcode().is_some() {
+            trace(&mut a, i);
+        }
+        if let Command::Mark(ref label) = command {
+            lowering.flush(&mut a);
+            // If a label is marked twice, jumps go to the first one, as they
+            // do in `Cfg`.
+            if marked.insert(label.clone()) {
+                let target = a.named(label);
+                a.bind(target);
+                context.labels.insert(label.clone(), a.len());
+            }
+            if a.is_limiting_steps() {
+                step(&mut a);
+            }
+        } else {
+            // Where a conditional jump falls through to, or a call returns
+            // to, starts a block too.
+            let ends_block = matches!(command,
+                                      Command::JumpZero(_) |
+                                      Command::JumpNegative(_) |
+                                      Command::Call(_));
+            lowering.lower(command.clone(), &mut a);
+            if ends_block && a.is_limiting_steps() {
+                lowering.flush(&mut a);
+                step(&mut a);
+            }
+        }
+        map.push((command, start..a.len()));
+    }
+    stubs(&mut a);
+    a.finish().map(|code| (code, map))
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::IResult;
+    use parsers;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+    use super::*;
+    use load;
+
+    #[test]
+    fn test_encode_number() {
+        assert_eq!(encode_number(0), b" \n".to_vec());
+        assert_eq!(encode_number(5), b" \t \t\n".to_vec());
+        assert_eq!(encode_number(-42), b"\t\t \t \t \n".to_vec());
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        // Every instruction has a unique encoding, and none is a prefix of
+        // another, or parsing would be ambiguous.
+        for a in OPCODES {
+            for b in OPCODES {
+                if !::std::ptr::eq(a, b) {
+                    assert!(!b.encoding().starts_with(&a.encoding()),
+                            "{} is a prefix of {}",
+                            a.mnemonic,
+                            b.mnemonic);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(Command::Push(3).stack_effect(), (0, 1));
+        assert_eq!(Command::Copy(2).stack_effect(), (3, 4));
+        assert_eq!(Command::Slide(2).stack_effect(), (3, 1));
+        assert_eq!(Command::Initialize.stack_effect(), (0, 0));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = vec![Command::Mark(Label::Name(vec![false, true])),
+                           Command::Push(-3),
+                           Command::JumpZero(Label::Name(vec![true]))];
+        assert_eq!(disassemble(&program),
+                   "mark 01\n\
+                    \x20   push -3                  ; 0 -> 1\n\
+                    \x20   jz 1                     ; 1 -> 0\n");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let program = vec![Command::Push(-17),
+                           Command::Mark(Label::Name(vec![true, false])),
+                           Command::Copy(3),
+                           Command::Slide(2),
+                           Command::JumpNegative(Label::Name(vec![false])),
+                           Command::Store,
+                           Command::ReadNum,
+                           Command::Return,
+                           Command::Exit];
+        match parsers::program(&encode_program(&program)) {
+            IResult::Done(rest, parsed) => {
+                assert!(rest.is_empty());
+                assert_eq!(parsed, program);
+            }
+            _ => panic!("encoded program not parsed"),
+        }
+    }
+
+    /// Prints a counter `calls` times on each of `n` times around a loop,
+    /// calling a helper each time.
+    fn calls(calls: usize, n: Number) -> Vec<Command> {
+        let l = |bits: &[bool]| Label::Name(bits.to_vec());
+        let mut program = vec![Command::Initialize, Command::Push(n), Command::Mark(l(&[false]))];
+        program.extend(vec![Command::OutputNum; calls]);
+        program.extend(vec![Command::Push(1),
+                            Command::Subtract,
+                            Command::Duplicate,
+                            Command::JumpZero(l(&[true])),
+                            Command::Jump(l(&[false])),
+                            Command::Mark(l(&[true])),
+                            Command::Exit,
+                            Command::Deinitialize]);
+        program
+    }
+
+    fn link_inline(program: Vec<Command>) -> Vec<u8> {
+        let mut a = Assembler::new();
+        a.set_inline_helpers(true);
+        link_with(program, &mut Context::new(), a).unwrap().0
+    }
+
+    #[test]
+    fn test_stubs() {
+        let (code, _) = link(calls(100, 2), &mut Context::new()).unwrap();
+        let inline = link_inline(calls(100, 2));
+        // Each call is five bytes rather than ten, plus one stub.
+        assert!(code.len() + 400 < inline.len());
+
+        for code in [code, inline] {
+            let stdout = Rc::new(RefCell::new(Vec::new()));
+            let mut context = Context::new();
+            context.capture_stdout(stdout.clone());
+            load(&code).unwrap().execute(&mut context);
+            assert_eq!(*stdout.borrow(), [b"2".repeat(100), b"1".repeat(100)].concat());
+        }
+    }
+
+    #[test]
+    fn test_blinding() {
+        let constant = 0x4142_4344_4546_4748;
+        let program = vec![Command::Push(constant),
+                           Command::Push(0x1_0000),
+                           Command::Add,
+                           Command::Push(constant),
+                           Command::Copy(1),
+                           Command::Copy(0x0123_4567),
+                           Command::Slide(0x0bad_cafe),
+                           Command::OutputNum];
+        let contains = |code: &[u8], bytes: &[u8]| code.windows(bytes.len()).any(|w| w == bytes);
+
+        let (code, _) = ::link(program.clone(), &mut Context::new(), false);
+        assert!(contains(&code, &constant.to_le_bytes()));
+        assert!(contains(&code, &(-8 * 0x0123_4568i32).to_le_bytes()));
+
+        let (blinded, _) = ::link(program.clone(), &mut Context::new(), true);
+        for n in [constant, constant + 0x1_0000] {
+            assert!(!contains(&blinded, &n.to_le_bytes()[..4]));
+            assert!(!contains(&blinded, &n.to_le_bytes()[4..]));
+        }
+        assert!(!contains(&blinded, &(-8 * 0x0123_4568i32).to_le_bytes()));
+        assert!(!contains(&blinded, &0x0bad_cafeu32.to_le_bytes()));
+        // A new key each time.
+        assert_ne!(::link(program.clone(), &mut Context::new(), true).0, blinded);
+
+        let run = |code: &[u8]| {
+            let stdout = Rc::new(RefCell::new(Vec::new()));
+            let mut context = Context::new();
+            context.capture_stdout(stdout.clone());
+            load(code).unwrap().execute(&mut context);
+            let stdout = stdout.borrow().clone();
+            (stdout, context.stack)
+        };
+        assert_eq!(run(&blinded), run(&code));
+    }
+
+    #[test]
+    fn test_marked_twice() {
+        let l = Label::Name(vec![true]);
+        let program = vec![Command::Push(1),
+                           Command::Jump(l.clone()),
+                           Command::Mark(l.clone()),
+                           Command::Push(2),
+                           Command::Exit,
+                           Command::Mark(l),
+                           Command::Push(3),
+                           Command::Exit];
+        assert_eq!(::run_captured(program, b"").stack, vec![1, 2]);
+    }
+
+    /// Compares the size and speed of calling helpers through stubs with
+    /// calling them inline. Run it with
+    /// `cargo test --release bench_helper_calls -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_helper_calls() {
+        let program = calls(1000, 10_000);
+        let time = |code: &[u8]| {
+            let function = load(code).unwrap();
+            let mut context = Context::new();
+            context.capture_stdout(Rc::new(RefCell::new(Vec::new())));
+            let start = Instant::now();
+            function.execute(&mut context);
+            start.elapsed()
+        };
+        let (stubs, _) = link(program.clone(), &mut Context::new()).unwrap();
+        let inline = link_inline(program);
+        println!("stubs:  {:>6} bytes, {:?}", stubs.len(), time(&stubs));
+        println!("inline: {:>6} bytes, {:?}", inline.len(), time(&inline));
+    }
+}
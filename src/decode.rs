@@ -0,0 +1,503 @@
+//! Decodes the instructions the assembler emits, so generated code can be
+//! read back. Anything else is shown as a bad byte and skipped.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem::offset_of;
+use std::ops::Range;
+
+use command::{Command, CONTEXT};
+use frame::HELPERS;
+use helpers;
+use wsstd::Context;
+
+const REGISTERS: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8",
+                               "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+
+/// Condition codes, as used in `jcc`.
+const CONDITIONS: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p",
+                                "np", "l", "ge", "le", "g"];
+
+/// One decoded instruction.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Instruction {
+    pub offset: usize,
+    pub len: usize,
+    pub text: String,
+    /// Where a jump or call goes, as an offset into the code.
+    pub target: Option<usize>,
+    /// The immediate loaded by `mov reg, imm64`, which is usually an address.
+    pub imm: Option<u64>,
+    /// The base register and displacement of the memory operand, if any.
+    pub mem: Option<(u8, i32)>,
+}
+
+/// The register or memory operand of a ModRM byte.
+enum Operand {
+    Reg(usize),
+    Mem(usize, i32),
+}
+
+impl Operand {
+    fn show(&self, size: &str) -> String {
+        match *self {
+            Operand::Reg(r) => REGISTERS[r].to_string(),
+            Operand::Mem(base, 0) => format!("{}[{}]", size, REGISTERS[base]),
+            Operand::Mem(base, disp) if disp < 0 => {
+                format!("{}[{} - {:#x}]", size, REGISTERS[base], -(disp as i64))
+            }
+            Operand::Mem(base, disp) => format!("{}[{} + {:#x}]", size, REGISTERS[base], disp),
+        }
+    }
+}
+
+struct Reader<'a> {
+    code: &'a [u8],
+    pos: usize,
+    // The REX prefix, or 0 if there wasn't one.
+    rex: u8,
+    mem: Option<(u8, i32)>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.code.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn i8(&mut self) -> Option<i64> {
+        self.u8().map(|b| b as i8 as i64)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn i32(&mut self) -> Option<i64> {
+        self.take(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// The register encoded in the low bits of an opcode.
+    fn opcode_reg(&self, op: u8) -> &'static str {
+        REGISTERS[(op & 7 | (self.rex & 1) << 3) as usize]
+    }
+
+    /// Reads a ModRM byte, returning its reg field and its other operand.
+    fn modrm(&mut self) -> Option<(usize, Operand)> {
+        let modrm = self.u8()?;
+        let reg = ((modrm >> 3) & 7 | (self.rex & 4) << 1) as usize;
+        let rm = (modrm & 7 | (self.rex & 1) << 3) as usize;
+        let operand = match modrm >> 6 {
+            3 => Operand::Reg(rm),
+            mode => {
+                // Only the SIB byte for a plain base is supported.
+                if modrm & 7 == 4 && self.u8()? != 0x24 {
+                    return None;
+                }
+                let disp = match mode {
+                    // This would be rip-relative, which we never use.
+                    0 if modrm & 7 == 5 => return None,
+                    0 => 0,
+                    1 => self.i8()?,
+                    _ => self.i32()?,
+                };
+                self.mem = Some((rm as u8, disp as i32));
+                Operand::Mem(rm, disp as i32)
+            }
+        };
+        Some((reg, operand))
+    }
+
+    /// A jump's displacement, turned into an offset into the code.
+    fn target(&mut self, rel: Option<i64>) -> Option<usize> {
+        rel.map(|rel| (self.pos as i64 + rel) as usize)
+    }
+}
+
+/// Decodes the instruction at `offset`.
+pub fn decode(code: &[u8], offset: usize) -> Instruction {
+    let mut reader = Reader {
+        code,
+        pos: offset,
+        rex: 0,
+        mem: None,
+    };
+    let mut instruction = Instruction {
+        offset,
+        len: 1,
+        text: String::new(),
+        target: None,
+        imm: None,
+        mem: None,
+    };
+    match decode_into(&mut reader, &mut instruction) {
+        Some(text) => {
+            instruction.len = reader.pos - offset;
+            instruction.text = text;
+            instruction.mem = reader.mem;
+        }
+        None => {
+            instruction.text = format!("(bad) {:#04x}", code[offset]);
+            instruction.target = None;
+            instruction.imm = None;
+        }
+    }
+    instruction
+}
+
+fn decode_into(r: &mut Reader, instruction: &mut Instruction) -> Option<String> {
+    let mut op = r.u8()?;
+    if op & 0xf0 == 0x40 {
+        r.rex = op;
+        op = r.u8()?;
+    }
+    let wide = r.rex & 8 != 0;
+    let text = match op {
+        0x50..=0x57 => format!("push {}", r.opcode_reg(op)),
+        0x58..=0x5f => format!("pop {}", r.opcode_reg(op)),
+        0xb8..=0xbf if wide => {
+            let imm = r.u64()?;
+            instruction.imm = Some(imm);
+            format!("mov {}, {:#x}", r.opcode_reg(op), imm)
+        }
+        0x99 if wide => "cqo".to_string(),
+        // op r/m, reg
+        0x01 | 0x29 | 0x31 | 0x85 | 0x89 if wide => {
+            let (reg, rm) = r.modrm()?;
+            let name = match op {
+                0x01 => "add",
+                0x29 => "sub",
+                0x31 => "xor",
+                0x85 => "test",
+                _ => "mov",
+            };
+            format!("{} {}, {}", name, rm.show(""), REGISTERS[reg])
+        }
+        // op reg, r/m
+        0x03 | 0x3b | 0x8b | 0x8d if wide => {
+            let (reg, rm) = r.modrm()?;
+            let name = match op {
+                0x03 => "add",
+                0x3b => "cmp",
+                0x8b => "mov",
+                _ => "lea",
+            };
+            format!("{} {}, {}", name, REGISTERS[reg], rm.show(""))
+        }
+        0x0f => {
+            match r.u8()? {
+                0xaf if wide => {
+                    let (reg, rm) = r.modrm()?;
+                    format!("imul {}, {}", REGISTERS[reg], rm.show(""))
+                }
+                op @ 0x80..=0x8f => {
+                    let rel = r.i32();
+                    instruction.target = r.target(rel);
+                    format!("j{} {:#x}", CONDITIONS[(op & 0xf) as usize], instruction.target?)
+                }
+                _ => return None,
+            }
+        }
+        0x83 | 0x81 if wide => {
+            let (extension, rm) = r.modrm()?;
+            let imm = if op == 0x83 { r.i8()? } else { r.i32()? };
+            let name = match extension {
+                0 => "add",
+                5 => "sub",
+                7 => "cmp",
+                _ => return None,
+            };
+            format!("{} {}, {:#x}", name, rm.show(""), imm)
+        }
+        0xc1 if wide => {
+            match r.modrm()? {
+                (4, rm) => format!("shl {}, {:#x}", rm.show(""), r.u8()?),
+                _ => return None,
+            }
+        }
+        0xf7 if wide => {
+            match r.modrm()? {
+                (7, rm) => format!("idiv {}", rm.show("")),
+                _ => return None,
+            }
+        }
+        0xc6 => {
+            match r.modrm()? {
+                (0, rm @ Operand::Mem(..)) => format!("mov {}, {:#x}", rm.show("byte "), r.u8()?),
+                _ => return None,
+            }
+        }
+        0x80 => {
+            match r.modrm()? {
+                (7, rm @ Operand::Mem(..)) => format!("cmp {}, {:#x}", rm.show("byte "), r.u8()?),
+                _ => return None,
+            }
+        }
+        0xff => {
+            match r.modrm()? {
+                (2, rm) => format!("call {}", rm.show("")),
+                (4, rm) => format!("jmp {}", rm.show("")),
+                _ => return None,
+            }
+        }
+        0x70..=0x7f => {
+            let rel = r.i8();
+            instruction.target = r.target(rel);
+            format!("j{} {:#x}", CONDITIONS[(op & 0xf) as usize], instruction.target?)
+        }
+        0xe8 | 0xe9 | 0xeb => {
+            let rel = if op == 0xeb { r.i8() } else { r.i32() };
+            instruction.target = r.target(rel);
+            let name = if op == 0xe8 { "call" } else { "jmp" };
+            format!("{} {:#x}", name, instruction.target?)
+        }
+        0xc2 => format!("ret {}", r.u16()?),
+        0xc3 => "ret".to_string(),
+        0xcc => "int3".to_string(),
+        _ => return None,
+    };
+    Some(text)
+}
+
+/// Names for the fields of the context generated code uses, by offset.
+fn fields() -> HashMap<i32, &'static str> {
+    macro_rules! fields {
+        ($($($field:ident).+),*) => {
+            vec![$((offset_of!(Context, $($field).+) as i32,
+                    concat!("context", $(".", stringify!($field)),+))),*]
+        }
+    }
+    fields!(native_base, native_limit, heap.native_base, heap.native_len).into_iter().collect()
+}
+
+/// What a memory operand refers to, if it's somewhere generated code knows
+/// about: a field of the context, the table of helpers, or a helper in it.
+fn operand(instruction: &Instruction, fields: &HashMap<i32, &'static str>) -> Option<&'static str> {
+    let (base, disp) = instruction.mem?;
+    if base == CONTEXT.number() {
+        fields.get(&disp).cloned()
+    } else if base == HELPERS.base.number() && disp == HELPERS.disp {
+        Some("helpers")
+    } else if instruction.text.starts_with("call [") || instruction.text.starts_with("jmp [") {
+        helpers::name(disp)
+    } else {
+        None
+    }
+}
+
+/// Shows some generated code as assembly, under the command each part of it
+/// came from. `map` gives the code for each command, as from
+/// `command::link_with`, and anything after the last command is the stubs which
+/// call helpers.
+pub fn dump(code: &[u8], map: &[(Command, Range<usize>)], c: &Context) -> String {
+    let fields = fields();
+    let marks = c.labels
+                 .iter()
+                 .map(|(label, &address)| (address, label))
+                 .collect::<HashMap<_, _>>();
+
+    // Each stub ends by jumping to its helper.
+    let end = map.last().map_or(0, |(_, range)| range.end);
+    let mut stubs = HashMap::new();
+    let (mut start, mut offset) = (end, end);
+    while offset < code.len() {
+        let instruction = decode(code, offset);
+        offset += instruction.len;
+        if instruction.text.starts_with("jmp [") {
+            if let Some(name) = operand(&instruction, &fields) {
+                stubs.insert(start, name);
+            }
+            start = offset;
+        }
+    }
+
+    let mut sections = map.iter()
+                          .map(|(command, range)| {
+                              let header = if command.opcode().is_some() {
+                                  format!("; {}", command)
+                              } else {
+                                  command.to_string()
+                              };
+                              (header, range.clone())
+                          })
+                          .collect::<Vec<_>>();
+    if end < code.len() {
+        sections.push(("; stubs".to_string(), end..code.len()));
+    }
+
+    let mut out = String::new();
+    for (header, range) in sections {
+        writeln!(out, "{}", header).unwrap();
+        let mut offset = range.start;
+        while offset < range.end {
+            let instruction = decode(code, offset);
+            let bytes = &code[offset..offset + instruction.len];
+            let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+            write!(out, "{:06x}  {:<30} {}", offset, hex, instruction.text).unwrap();
+            if let Some(name) = operand(&instruction, &fields) {
+                write!(out, "  ; {}", name).unwrap();
+            }
+            if let Some(name) = instruction.target.and_then(|target| stubs.get(&target)) {
+                write!(out, "  ; {}", name).unwrap();
+            }
+            if let Some(label) = instruction.target.and_then(|target| marks.get(&target)) {
+                write!(out, "  ; to mark {}", label).unwrap();
+            }
+            out.push('\n');
+            offset += instruction.len;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::{Assembler, Cond, Mem};
+    use asm::Reg::*;
+    use command;
+    use Label;
+
+    /// Decodes all of some code.
+    fn decode_all(code: &[u8]) -> Vec<Instruction> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < code.len() {
+            let instruction = decode(code, offset);
+            offset += instruction.len;
+            instructions.push(instruction);
+        }
+        instructions
+    }
+
+    fn text<F: FnOnce(&mut Assembler)>(f: F) -> String {
+        let mut a = Assembler::new();
+        f(&mut a);
+        let code = a.finish().unwrap();
+        let instructions = decode_all(&code);
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(instructions[0].len, code.len());
+        instructions[0].text.clone()
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(text(|a| a.mov(Rbx, Rax)), "mov rbx, rax");
+        assert_eq!(text(|a| a.mov(Rsi, R12)), "mov rsi, r12");
+        assert_eq!(text(|a| a.mov_imm(R14, 0x1234)), "mov r14, 0x1234");
+        assert_eq!(text(|a| a.load(Rax, Mem::new(R15, 0))), "mov rax, [r15]");
+        assert_eq!(text(|a| a.store(Mem::new(R15, 0), Rsi)), "mov [r15], rsi");
+        assert_eq!(text(|a| a.load(Rax, Mem::new(R12, 0))), "mov rax, [r12]");
+        assert_eq!(text(|a| a.load(Rax, Mem::new(R13, 0))), "mov rax, [r13]");
+        assert_eq!(text(|a| a.lea(Rsp, Mem::new(Rbp, -0x28))), "lea rsp, [rbp - 0x28]");
+        assert_eq!(text(|a| a.lea(Rax, Mem::new(R15, 0x1000))), "lea rax, [r15 + 0x1000]");
+        assert_eq!(text(|a| a.store_byte(Mem::new(Rax, 8), 1)), "mov byte [rax + 0x8], 0x1");
+        assert_eq!(text(|a| a.cmp_byte(Mem::new(Rax, 8), 0)), "cmp byte [rax + 0x8], 0x0");
+        assert_eq!(text(|a| a.cmp_mem(R15, Mem::new(Rax, 0))), "cmp r15, [rax]");
+        assert_eq!(text(|a| a.add_mem(Rax, Mem::new(Rcx, 0))), "add rax, [rcx]");
+        assert_eq!(text(|a| a.add(Rax, R12)), "add rax, r12");
+        assert_eq!(text(|a| a.sub(R13, Rbx)), "sub r13, rbx");
+        assert_eq!(text(|a| a.xor(Rsi, R11)), "xor rsi, r11");
+        assert_eq!(text(|a| a.imul(Rax, R12)), "imul rax, r12");
+        assert_eq!(text(|a| a.idiv(Rcx)), "idiv rcx");
+        assert_eq!(text(|a| a.cqo()), "cqo");
+        assert_eq!(text(|a| a.test(R14, R14)), "test r14, r14");
+        assert_eq!(text(|a| a.add_imm(R15, 8)), "add r15, 0x8");
+        assert_eq!(text(|a| a.sub_imm(Rsp, 0x1000)), "sub rsp, 0x1000");
+        assert_eq!(text(|a| a.shl_imm(Rax, 4)), "shl rax, 0x4");
+        assert_eq!(text(|a| a.push(R12)), "push r12");
+        assert_eq!(text(|a| a.pop(Rbp)), "pop rbp");
+        assert_eq!(text(|a| a.call_mem(Mem::new(Rax, 8))), "call [rax + 0x8]");
+        assert_eq!(text(|a| a.jmp_mem(Mem::new(Rax, 8))), "jmp [rax + 0x8]");
+        assert_eq!(text(|a| a.ret()), "ret");
+        assert_eq!(text(|a| a.ret_imm(8)), "ret 8");
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut a = Assembler::new();
+        let (top, end) = (a.new_label(), a.new_label());
+        a.bind(top);
+        a.jcc_short(Cond::Ae, end);
+        a.jcc(Cond::S, top);
+        a.call(top);
+        a.jmp(end);
+        a.bind(end);
+        a.jmp_short(top);
+        let instructions = decode_all(&a.finish().unwrap());
+        let texts = instructions.iter().map(|i| &i.text[..]).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["jae 0x12", "js 0x0", "call 0x0", "jmp 0x12", "jmp 0x0"]);
+        assert_eq!(instructions[0].target, Some(0x12));
+    }
+
+    #[test]
+    fn test_bad() {
+        let instructions = decode_all(&[0x0f, 0x0b, 0xc3]);
+        assert_eq!(instructions[0].text, "(bad) 0x0f");
+        assert_eq!(instructions[1].text, "(bad) 0x0b");
+        assert_eq!(instructions[2].text, "ret");
+        // Cut off part way through.
+        assert_eq!(decode_all(&[0x48, 0xb8, 0x01])[0].text, "(bad) 0x48");
+    }
+
+    #[test]
+    fn test_decodes_generated_code() {
+        // Every command, lowered or not, decodes without anything left over.
+        let l = Label::Name(vec![true]);
+        let program = vec![Command::Initialize,
+                           Command::Push(1),
+                           Command::Duplicate,
+                           Command::Copy(1),
+                           Command::Swap,
+                           Command::Slide(1),
+                           Command::Add,
+                           Command::Duplicate,
+                           Command::Subtract,
+                           Command::Push(3),
+                           Command::Multiply,
+                           Command::Push(2),
+                           Command::Divide,
+                           Command::Push(2),
+                           Command::Modulus,
+                           Command::Store,
+                           Command::Retrieve,
+                           Command::OutputNum,
+                           Command::OutputChar,
+                           Command::ReadNum,
+                           Command::ReadChar,
+                           Command::Mark(l.clone()),
+                           Command::Call(l.clone()),
+                           Command::JumpZero(l.clone()),
+                           Command::JumpNegative(l.clone()),
+                           Command::Jump(l),
+                           Command::Pop,
+                           Command::Return,
+                           Command::Exit,
+                           Command::Deinitialize];
+        let mut context = Context::new();
+        let (code, map) = command::link(program, &mut context).unwrap();
+        assert!(decode_all(&code).iter().all(|i| !i.text.starts_with("(bad)")));
+
+        let dump = dump(&code, &map, &context);
+        assert!(dump.starts_with("; Initialize\n000000  55"));
+        assert!(dump.contains("; retrieve\n"));
+        assert!(dump.contains("; Context::print\n"));
+        assert!(dump.contains("[rbp - 0x30]  ; helpers\n"));
+        assert!(dump.contains("\n; stubs\n"));
+        assert!(dump.contains("[r14 + 0x"));
+        assert!(dump.contains("; context.native_base\n"));
+        assert!(dump.contains("; context.heap.native_len\n"));
+        assert!(dump.contains("; to mark 1\n"));
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use cfg::{BlockId, Cfg, Edge};
+use command::Command;
+use Label;
+
+/// How many items might be on the stack at some point in a program.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Depth {
+    pub min: usize,
+    /// `None` if there's no limit, such as in a loop which pushes.
+    pub max: Option<usize>,
+}
+
+impl Depth {
+    pub fn exactly(n: usize) -> Self {
+        Depth { min: n, max: Some(n) }
+    }
+
+    /// Anything at all.
+    pub fn unknown() -> Self {
+        Depth { min: 0, max: None }
+    }
+
+    /// A depth covering both.
+    pub fn join(self, other: Depth) -> Self {
+        Depth {
+            min: self.min.min(other.min),
+            max: match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            },
+        }
+    }
+
+    /// The depth after popping `consumes` items and pushing `produces`.
+    /// Popping an empty stack is an error at runtime, but leaves it empty.
+    pub fn apply(self, consumes: usize, produces: usize) -> Self {
+        Depth {
+            min: self.min.saturating_sub(consumes) + produces,
+            max: self.max.map(|max| max.saturating_sub(consumes) + produces),
+        }
+    }
+}
+
+impl fmt::Display for Depth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}..{}", self.min, max),
+            None => write!(f, "{}..", self.min),
+        }
+    }
+}
+
+/// How a subroutine changes the stack, from the call to the return.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Signature {
+    /// It needs `consumes` items on the stack, and leaves `produces` items in
+    /// their place, whichever way it goes.
+    Fixed { consumes: usize, produces: usize },
+    /// It never returns.
+    NoReturn,
+    /// How many items it leaves depends on the path it takes.
+    Unbalanced,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Signature::Fixed { consumes, produces } => write!(f, "{} -> {}", consumes, produces),
+            Signature::NoReturn => write!(f, "never returns"),
+            Signature::Unbalanced => write!(f, "unbalanced"),
+        }
+    }
+}
+
+/// A command which might pop more items than there are on the stack.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Underflow {
+    pub index: usize,
+    /// Whether it always underflows, rather than only on some paths.
+    pub definite: bool,
+}
+
+/// What we can work out about the stack without running a program.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct StackDepths {
+    /// The depth before each command, or `None` if it can never run.
+    pub depths: Vec<Option<Depth>>,
+    pub underflows: Vec<Underflow>,
+    /// The signature of each subroutine which is called, by its label.
+    pub signatures: HashMap<Label, Signature>,
+}
+
+/// Where a subroutine has got to, relative to the depth it was called with.
+struct Relative {
+    /// How far below the starting depth it has needed to go.
+    consumes: usize,
+    /// The depth at each return, if they've all been the same.
+    returns: Option<isize>,
+    balanced: bool,
+}
+
+/// Works out the signature of the subroutine starting at `start`, given the
+/// signatures we have so far for the ones it calls.
+fn signature(cfg: &Cfg, start: BlockId, signatures: &BTreeMap<BlockId, Signature>) -> Signature {
+    let mut entry: Vec<Option<isize>> = vec![None; cfg.blocks.len()];
+    let mut relative = Relative {
+        consumes: 0,
+        returns: None,
+        balanced: true,
+    };
+
+    entry[start] = Some(0);
+    let mut todo = vec![start];
+    while let Some(id) = todo.pop() {
+        let mut depth = entry[id].unwrap();
+        for command in cfg.commands(id) {
+            let (consumes, produces) = command.stack_effect();
+            let needed = consumes as isize - depth;
+            if needed > relative.consumes as isize {
+                relative.consumes = needed as usize;
+            }
+            depth += produces as isize - consumes as isize;
+            if *command == Command::Return {
+                match relative.returns {
+                    Some(other) if other != depth => relative.balanced = false,
+                    _ => relative.returns = Some(depth),
+                }
+            }
+        }
+
+        for &(to, edge) in &cfg.successors[id] {
+            let after = match edge {
+                Edge::Call => continue,
+                Edge::Fallthrough => {
+                    match after_call(cfg, id, signatures) {
+                        Some(Signature::Fixed { consumes, produces }) => {
+                            let needed = consumes as isize - depth;
+                            if needed > relative.consumes as isize {
+                                relative.consumes = needed as usize;
+                            }
+                            depth - consumes as isize + produces as isize
+                        }
+                        Some(Signature::NoReturn) => continue,
+                        Some(Signature::Unbalanced) => return Signature::Unbalanced,
+                        None => depth,
+                    }
+                }
+                Edge::Taken => depth,
+            };
+            match entry[to] {
+                Some(other) if other != after => return Signature::Unbalanced,
+                Some(_) => {}
+                None => {
+                    entry[to] = Some(after);
+                    todo.push(to);
+                }
+            }
+        }
+    }
+
+    match relative.returns {
+        _ if !relative.balanced => Signature::Unbalanced,
+        Some(depth) => {
+            Signature::Fixed {
+                consumes: relative.consumes,
+                produces: (relative.consumes as isize + depth) as usize,
+            }
+        }
+        None => Signature::NoReturn,
+    }
+}
+
+/// If the block ends in a call, the signature of what it calls. Calls to
+/// labels which are never marked count as unbalanced.
+fn after_call(cfg: &Cfg,
+              id: BlockId,
+              signatures: &BTreeMap<BlockId, Signature>)
+              -> Option<Signature> {
+    match cfg.commands(id).last() {
+        Some(Command::Call(l)) => {
+            Some(cfg.block_of(l)
+                    .and_then(|target| signatures.get(&target).cloned())
+                    .unwrap_or(Signature::Unbalanced))
+        }
+        _ => None,
+    }
+}
+
+/// Works out the signature of every subroutine. Starting from none of them
+/// returning, and recomputing until nothing changes, handles recursion. If
+/// that doesn't settle, whatever's still changing is unbalanced.
+fn signatures(cfg: &Cfg) -> BTreeMap<BlockId, Signature> {
+    let mut signatures = BTreeMap::new();
+    for &(to, edge) in cfg.successors.iter().flat_map(|s| s.iter()) {
+        if edge == Edge::Call {
+            signatures.insert(to, Signature::NoReturn);
+        }
+    }
+
+    for round in 0.. {
+        let mut changed = false;
+        for start in signatures.keys().cloned().collect::<Vec<BlockId>>() {
+            let new = signature(cfg, start, &signatures);
+            if new != signatures[&start] {
+                changed = true;
+                let settled = round <= 2 * signatures.len();
+                signatures.insert(start, if settled { new } else { Signature::Unbalanced });
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    signatures
+}
+
+impl StackDepths {
+    pub fn of(program: &[Command]) -> Self {
+        let cfg = Cfg::new(program);
+        let signatures = signatures(&cfg);
+
+        let mut entry: Vec<Option<Depth>> = vec![None; cfg.blocks.len()];
+        let mut visits = vec![0; cfg.blocks.len()];
+        let mut depths = vec![None; program.len()];
+        let mut todo = vec![];
+        if !cfg.blocks.is_empty() {
+            entry[0] = Some(Depth::exactly(0));
+            todo.push(0);
+        }
+
+        while let Some(id) = todo.pop() {
+            let mut depth = entry[id].unwrap();
+            for (index, command) in cfg.blocks[id].range.clone().zip(cfg.commands(id)) {
+                depths[index] = Some(depth);
+                let (consumes, produces) = command.stack_effect();
+                depth = depth.apply(consumes, produces);
+            }
+
+            for &(to, edge) in &cfg.successors[id] {
+                let after = match (edge, after_call(&cfg, id, &signatures)) {
+                    (Edge::Fallthrough, Some(Signature::Fixed { consumes, produces })) => {
+                        depth.apply(consumes, produces)
+                    }
+                    (Edge::Fallthrough, Some(Signature::NoReturn)) => continue,
+                    (Edge::Fallthrough, Some(Signature::Unbalanced)) => Depth::unknown(),
+                    _ => depth,
+                };
+                let joined = match entry[to] {
+                    Some(old) => {
+                        let mut joined = old.join(after);
+                        // Loops which keep pushing would never settle.
+                        if visits[to] > 2 && joined.max != old.max {
+                            joined.max = None;
+                        }
+                        joined
+                    }
+                    None => after,
+                };
+                if entry[to] != Some(joined) {
+                    entry[to] = Some(joined);
+                    visits[to] += 1;
+                    todo.push(to);
+                }
+            }
+        }
+
+        let underflows = program.iter()
+                                .zip(&depths)
+                                .enumerate()
+                                .filter_map(|(index, (command, depth))| {
+                                    let depth = (*depth)?;
+                                    let (consumes, _) = command.stack_effect();
+                                    if depth.min >= consumes {
+                                        return None;
+                                    }
+                                    Some(Underflow {
+                                        index,
+                                        definite: depth.max.is_some_and(|max| max < consumes),
+                                    })
+                                })
+                                .collect();
+
+        let signatures = signatures.into_iter()
+                                   .filter_map(|(id, signature)| {
+                                       cfg.blocks[id].label.clone().map(|l| (l, signature))
+                                   })
+                                   .collect();
+
+        StackDepths {
+            depths,
+            underflows,
+            signatures,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use l;
+
+    #[test]
+    fn test_straight_line() {
+        let program = vec![Command::Push(1),
+                           Command::Push(2),
+                           Command::Add,
+                           Command::Pop,
+                           Command::Pop,
+                           Command::Exit];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.depths[2], Some(Depth::exactly(2)));
+        assert_eq!(depths.depths[4], Some(Depth::exactly(0)));
+        assert_eq!(depths.underflows, vec![Underflow { index: 4, definite: true }]);
+    }
+
+    #[test]
+    fn test_branches() {
+        // Pushes one or two items depending on the input, then pops two.
+        let program = vec![Command::Push(0),
+                           Command::ReadNum,
+                           Command::Retrieve,
+                           Command::JumpZero(l("0")),
+                           Command::Push(1),
+                           Command::Mark(l("0")),
+                           Command::Add,
+                           Command::Exit];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.depths[6],
+                   Some(Depth {
+                       min: 1,
+                       max: Some(2),
+                   }));
+        assert_eq!(depths.underflows, vec![Underflow { index: 6, definite: false }]);
+    }
+
+    #[test]
+    fn test_loop_widens() {
+        let program = vec![Command::Mark(l("0")), Command::Push(1), Command::Jump(l("0"))];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.depths[0], Some(Depth { min: 0, max: None }));
+        assert!(depths.underflows.is_empty());
+    }
+
+    #[test]
+    fn test_signatures() {
+        let program = vec![Command::Push(1),
+                           Command::Push(2),
+                           Command::Call(l("1")),
+                           Command::OutputNum,
+                           Command::Call(l("10")),
+                           Command::Mark(l("1")),
+                           Command::Add,
+                           Command::Duplicate,
+                           Command::Return,
+                           Command::Mark(l("10")),
+                           Command::Exit];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.signatures[&l("1")],
+                   Signature::Fixed {
+                       consumes: 2,
+                       produces: 2,
+                   });
+        assert_eq!(depths.signatures[&l("10")], Signature::NoReturn);
+        assert_eq!(depths.depths[3], Some(Depth::exactly(2)));
+        assert_eq!(depths.depths[6], Some(Depth::exactly(2)));
+        assert!(depths.underflows.is_empty());
+    }
+
+    #[test]
+    fn test_recursion() {
+        // Pops until it finds a zero.
+        let program = vec![Command::Call(l("1")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::JumpZero(l("0")),
+                           Command::Call(l("1")),
+                           Command::Mark(l("0")),
+                           Command::Return];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.signatures[&l("1")], Signature::Unbalanced);
+
+        // Keeps the stack the same however deep it goes.
+        let program = vec![Command::Call(l("1")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Duplicate,
+                           Command::JumpZero(l("0")),
+                           Command::Push(1),
+                           Command::Subtract,
+                           Command::Call(l("1")),
+                           Command::Mark(l("0")),
+                           Command::Return];
+        let depths = StackDepths::of(&program);
+        assert_eq!(depths.signatures[&l("1")],
+                   Signature::Fixed {
+                       consumes: 1,
+                       produces: 1,
+                   });
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Depth::exactly(3).to_string(), "3");
+        assert_eq!(Depth { min: 1, max: Some(4) }.to_string(), "1..4");
+        assert_eq!(Depth::unknown().to_string(), "0..");
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use command::Command;
+use opcodes::Origin;
+
+/// A version of the language. Later versions only add instructions, so
+/// they're ordered by what they accept.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default)]
+pub enum Dialect {
+    V0_2,
+    /// Adds copy and slide.
+    #[default]
+    V0_3,
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Dialect::V0_2 => write!(f, "0.2"),
+            Dialect::V0_3 => write!(f, "0.3"),
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "0.2" => Ok(Dialect::V0_2),
+            "0.3" => Ok(Dialect::V0_3),
+            _ => Err(format!("unknown dialect {} (expected 0.2 or 0.3)", s)),
+        }
+    }
+}
+
+/// The instructions a program uses which aren't available everywhere.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Features {
+    /// The oldest dialect the program can run on.
+    pub dialect: Dialect,
+    /// How many times each instruction newer than 0.2, or each extension, is
+    /// used, by mnemonic.
+    pub uses: BTreeMap<&'static str, (Origin, usize)>,
+}
+
+impl Features {
+    pub fn of(program: &[Command]) -> Self {
+        let mut features = Features {
+            dialect: Dialect::V0_2,
+            uses: BTreeMap::new(),
+        };
+        for opcode in program.iter().filter_map(Command::opcode) {
+            match opcode.origin {
+                Origin::Standard(Dialect::V0_2) => continue,
+                Origin::Standard(dialect) => {
+                    features.dialect = features.dialect.max(dialect);
+                }
+                Origin::Extension => {}
+            }
+            features.uses.entry(opcode.mnemonic).or_insert((opcode.origin, 0)).1 += 1;
+        }
+        features
+    }
+
+    /// Whether the program uses any extensions.
+    pub fn extensions(&self) -> bool {
+        self.uses.values().any(|&(origin, _)| origin == Origin::Extension)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "requires Whitespace {}{}",
+                 self.dialect,
+                 if self.extensions() { " with extensions" } else { "" })?;
+        for (mnemonic, &(origin, count)) in &self.uses {
+            let origin = match origin {
+                Origin::Standard(dialect) => format!("since {}", dialect),
+                Origin::Extension => "extension".to_string(),
+            };
+            writeln!(f,
+                     "    {:<12} {:>4} use{} ({})",
+                     mnemonic,
+                     count,
+                     if count == 1 { "" } else { "s" },
+                     origin)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("0.2".parse(), Ok(Dialect::V0_2));
+        assert_eq!("0.3".parse(), Ok(Dialect::V0_3));
+        assert!("0.4".parse::<Dialect>().is_err());
+        assert!(Dialect::V0_2 < Dialect::V0_3);
+    }
+
+    #[test]
+    fn test_features() {
+        let features = Features::of(&[Command::Push(1), Command::Duplicate]);
+        assert_eq!(features.dialect, Dialect::V0_2);
+        assert!(features.uses.is_empty());
+
+        let features = Features::of(&[Command::Push(1),
+                                      Command::Copy(0),
+                                      Command::Copy(1),
+                                      Command::Slide(1)]);
+        assert_eq!(features.dialect, Dialect::V0_3);
+        assert!(!features.extensions());
+        assert_eq!(features.to_string(),
+                   "requires Whitespace 0.3\n\
+                    \x20   copy            2 uses (since 0.3)\n\
+                    \x20   slide           1 use (since 0.3)\n");
+    }
+}
@@ -0,0 +1,215 @@
+//! Turns faults in generated code into errors, rather than letting them kill
+//! the process. While guarded code is running, a SIGSEGV or SIGFPE whose PC
+//! is in that code returns from it straight away, just as `frame::leave`
+//! would, and the guard reports where it happened.
+//!
+//! Faults anywhere else, including in the helpers the code calls, go to
+//! whichever handler was there before.
+
+use libc as c;
+use std::cell::Cell;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::OnceLock;
+
+use asm::Reg;
+use command::{Command, SourceMap};
+use frame;
+
+const SIGNALS: [c::c_int; 2] = [c::SIGSEGV, c::SIGFPE];
+
+/// A fault in generated code.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Fault {
+    pub signal: c::c_int,
+    /// Where it happened, from the start of the code.
+    pub offset: usize,
+    /// The top of the stack at the time; see `stack.rs`.
+    pub top: usize,
+}
+
+impl Fault {
+    /// The command whose code faulted, if it was one of them rather than a
+    /// stub.
+    pub fn command<'a>(&self, map: &'a SourceMap) -> Option<&'a Command> {
+        map.iter()
+           .find(|(_, range)| range.contains(&self.offset))
+           .map(|(command, _)| command)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.signal {
+            c::SIGFPE => "arithmetic fault",
+            _ => "memory fault",
+        };
+        write!(f, "{} at {:#x}", what, self.offset)
+    }
+}
+
+thread_local! {
+    // The code being guarded on this thread, and the fault in it if there
+    // was one. These are read in the handler, so they mustn't need
+    // initializing lazily.
+    static GUARDED: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+    static FAULT: Cell<Option<Fault>> = const { Cell::new(None) };
+}
+
+/// The handlers which were there before ours, in the order of `SIGNALS`.
+static PREVIOUS: OnceLock<[c::sigaction; 2]> = OnceLock::new();
+
+/// Where the signal handler finds one of the saved registers.
+fn greg(r: Reg) -> c::c_int {
+    match r {
+        Reg::Rbx => c::REG_RBX,
+        Reg::R12 => c::REG_R12,
+        Reg::R13 => c::REG_R13,
+        Reg::R14 => c::REG_R14,
+        Reg::R15 => c::REG_R15,
+        _ => unreachable!("{:?} isn't saved", r),
+    }
+}
+
+extern "C" fn handle(signal: c::c_int, info: *mut c::siginfo_t, context: *mut c::c_void) {
+    let gregs = unsafe { &mut (*(context as *mut c::ucontext_t)).uc_mcontext.gregs };
+    let pc = gregs[c::REG_RIP as usize] as usize;
+    let start = match GUARDED.with(Cell::get) {
+        Some((start, end)) if (start..end).contains(&pc) => start,
+        _ => return unsafe { chain(signal, info, context) },
+    };
+    FAULT.with(|fault| {
+        fault.set(Some(Fault {
+            signal,
+            offset: pc - start,
+            top: gregs[c::REG_R15 as usize] as usize,
+        }))
+    });
+
+    // Generated code never moves `rbp`, so it's still the frame's.
+    unsafe {
+        let rbp = gregs[c::REG_RBP as usize] as *const i64;
+        for (i, &r) in frame::SAVED.iter().enumerate() {
+            gregs[greg(r) as usize] = *rbp.offset(-1 - i as isize);
+        }
+        gregs[c::REG_RSP as usize] = rbp.offset(2) as i64;
+        gregs[c::REG_RIP as usize] = *rbp.offset(1);
+        gregs[c::REG_RBP as usize] = *rbp;
+        gregs[c::REG_RAX as usize] = 0;
+    }
+}
+
+/// Passes a signal on to the handler which was there before ours.
+unsafe fn chain(signal: c::c_int, info: *mut c::siginfo_t, context: *mut c::c_void) {
+    let previous = &PREVIOUS.get().unwrap()[SIGNALS.iter().position(|&s| s == signal).unwrap()];
+    match previous.sa_sigaction {
+        // Put it back, so the fault happens again when we return and does
+        // whatever it would have.
+        c::SIG_DFL | c::SIG_IGN => {
+            c::sigaction(signal, previous, ptr::null_mut());
+        }
+        f if previous.sa_flags & c::SA_SIGINFO != 0 => {
+            let f: extern "C" fn(c::c_int, *mut c::siginfo_t, *mut c::c_void) = mem::transmute(f);
+            f(signal, info, context);
+        }
+        f => {
+            let f: extern "C" fn(c::c_int) = mem::transmute(f);
+            f(signal);
+        }
+    }
+}
+
+/// Puts our handler in place, once.
+fn install() {
+    PREVIOUS.get_or_init(|| {
+        SIGNALS.map(|signal| unsafe {
+            let mut action: c::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as *const () as usize;
+            // Recursing too deep faults with no stack left to handle it on,
+            // so use the alternate stack if there is one.
+            action.sa_flags = c::SA_SIGINFO | c::SA_ONSTACK;
+            c::sigemptyset(&mut action.sa_mask);
+            let mut previous = mem::zeroed();
+            assert_eq!(c::sigaction(signal, &action, &mut previous), 0);
+            previous
+        })
+    });
+}
+
+/// Runs `f`, which runs `code`, catching any fault in `code`.
+pub fn guard<T, F: FnOnce() -> T>(code: &[u8], f: F) -> Result<T, Fault> {
+    install();
+    let region = Some((code.as_ptr() as usize, code.as_ptr() as usize + code.len()));
+    let outer = GUARDED.with(|guarded| guarded.replace(region));
+    FAULT.with(|fault| fault.set(None));
+    let result = f();
+    GUARDED.with(|guarded| guarded.set(outer));
+    match FAULT.with(Cell::take) {
+        Some(fault) => Err(fault),
+        None => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::{Assembler, Mem};
+    use asm::Reg::*;
+    use wsstd::Context;
+    use {get_native_function, link, load, Label};
+
+    #[test]
+    fn test_memory_fault() {
+        let mut a = Assembler::new();
+        frame::enter(&mut a);
+        for &r in &frame::SAVED {
+            a.mov_imm(r, 0);
+        }
+        a.mov_imm(Rax, 0);
+        let offset = a.len();
+        a.load(Rax, Mem::new(Rax, 0));
+        frame::leave(&mut a);
+        let function = load(&a.finish().unwrap()).unwrap();
+
+        let fault = function.execute_guarded(&mut Context::new()).unwrap_err();
+        assert_eq!((fault.signal, fault.offset), (c::SIGSEGV, offset));
+        // Everything was put back, and it can run again.
+        assert_eq!(function.execute_guarded(&mut Context::new()), Err(fault));
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let program = vec![Command::Push(1),
+                           Command::Push(2),
+                           Command::Push(0),
+                           Command::Divide,
+                           Command::OutputNum];
+        let mut context = Context::new();
+        let (code, map) = link(program, &mut context, false);
+        let fault = load(&code).unwrap().execute_guarded(&mut context).unwrap_err();
+        assert_eq!(fault.signal, c::SIGFPE);
+        assert_eq!(fault.command(&map), Some(&Command::Divide));
+        assert_eq!(fault.to_string(), format!("arithmetic fault at {:#x}", fault.offset));
+    }
+
+    #[test]
+    fn test_recursion() {
+        // Calls itself until there's no stack left.
+        let l = Label::Name(vec![true]);
+        let program = vec![Command::Mark(l.clone()), Command::Call(l)];
+        let mut context = Context::new();
+        let (code, map) = link(program, &mut context, false);
+        let fault = load(&code).unwrap().execute_guarded(&mut context).unwrap_err();
+        assert_eq!(fault.signal, c::SIGSEGV);
+        assert_eq!(fault.command(&map), Some(&Command::Call(Label::Name(vec![true]))));
+    }
+
+    #[test]
+    fn test_no_fault() {
+        let program = vec![Command::Push(6), Command::Push(3), Command::Divide];
+        let mut context = Context::new();
+        assert!(get_native_function(program, &mut context).execute_guarded(&mut context).is_ok());
+        assert_eq!(context.stack, vec![2]);
+    }
+}
@@ -0,0 +1,185 @@
+//! The frame generated code runs in. The code is called under the System V
+//! calling convention, as `extern "C" fn(&mut Context, *const usize) -> i64`,
+//! and it calls helpers under it too. So it saves the callee-saved registers
+//! it uses on the way in and restores them on the way out, and keeps the
+//! stack aligned to 16 bytes at every call it makes.
+//!
+//! Calls within a program push a slot of padding as well as the return
+//! address (see `Command::Call`), so the stack is aligned at any depth.
+
+use asm::{Assembler, Mem, Reg};
+use asm::Reg::*;
+
+/// The callee-saved registers generated code uses: the ones lowering keeps
+/// stack items in, the context, and the top of the stack.
+pub const SAVED: [Reg; 5] = [Rbx, R12, R13, R14, R15];
+
+/// How many slots generated code keeps for itself below the saved registers.
+const LOCALS: i32 = 1;
+
+/// How far `rsp` is below `rbp` once the frame is set up. `rbp` is aligned,
+/// since the return address and the old `rbp` are above it, so this is
+/// rounded up to keep `rsp` aligned too.
+pub const SIZE: i32 = (8 * (SAVED.len() as i32 + LOCALS) + 15) & !15;
+
+/// Where the table of helpers the code was called with is kept.
+pub const HELPERS: Mem = Mem {
+    base: Rbp,
+    disp: -8 * (SAVED.len() as i32 + 1),
+};
+
+// The locals have to fit in the frame, which has to keep `rsp` aligned.
+const _: () = assert!(SIZE % 16 == 0 && HELPERS.disp >= -SIZE);
+
+/// Sets up the frame, leaving the arguments where they were.
+pub fn enter(a: &mut Assembler) {
+    a.push(Rbp);
+    a.mov(Rbp, Rsp);
+    for &r in &SAVED {
+        a.push(r);
+    }
+    a.sub_imm(Rsp, SIZE - 8 * SAVED.len() as i32);
+}
+
+/// Tears down the frame and returns, from however deep in the program's own
+/// calls we are.
+pub fn leave(a: &mut Assembler) {
+    a.lea(Rsp, Mem::new(Rbp, -8 * SAVED.len() as i32));
+    for &r in SAVED.iter().rev() {
+        a.pop(r);
+    }
+    a.pop(Rbp);
+    a.ret();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Command, CONTEXT};
+    use helpers::{self, Helper};
+    use libc as c;
+    use lower::REGISTERS;
+    use std::cell::RefCell;
+    use std::hint::black_box;
+    use std::mem::{transmute, MaybeUninit};
+    use std::rc::Rc;
+    use wsstd::Context;
+    use {link, load, Label, Number};
+
+    /// Something which has to be aligned to 16 bytes, as SSE needs.
+    #[repr(align(16))]
+    #[allow(dead_code)]
+    struct Aligned([u8; 16]);
+
+    /// Whether the stack was aligned when this was called. The compiler
+    /// assumes it was, so a local which needs aligning is only aligned if it
+    /// really was. It's never written, since writing it might fault if not.
+    #[inline(never)]
+    extern "C" fn misalignment() -> i64 {
+        let local = MaybeUninit::<Aligned>::uninit();
+        (black_box(local.as_ptr()) as usize % 16) as i64
+    }
+
+    thread_local! {
+        // Each call to a checked helper, and how misaligned it was.
+        static CHECKED: RefCell<Vec<(Helper, i64)>> = const { RefCell::new(vec![]) };
+    }
+
+    fn check(helper: Helper) {
+        let misalignment = misalignment();
+        if misalignment != 0 {
+            // The helper itself is likely to crash, so say why first.
+            let message = b"helper called with the stack misaligned\n";
+            unsafe { c::write(2, message.as_ptr() as *const c::c_void, message.len()) };
+        }
+        CHECKED.with(|checked| checked.borrow_mut().push((helper, misalignment)));
+    }
+
+    // Stand-ins for helpers, which check the stack on the way through.
+    extern "C" fn print(context: &mut Context, is_char: bool) {
+        check(Helper::Print);
+        unsafe { context.print(is_char) }
+    }
+
+    extern "C" fn grow_native(context: &mut Context, top: *mut Number) -> *mut Number {
+        check(Helper::GrowNative);
+        unsafe { context.grow_native(top) }
+    }
+
+    /// Where generated code can find `misalignment`, to call it.
+    static MISALIGNMENT: extern "C" fn() -> i64 = misalignment;
+
+    fn call_misalignment(a: &mut Assembler) {
+        a.mov_imm(Rax, &MISALIGNMENT as *const _ as u64);
+        a.call_mem(Mem::new(Rax, 0));
+    }
+
+    #[test]
+    fn test_saved() {
+        let callee_saved = [Rbx, Rbp, R12, R13, R14, R15];
+        assert!(SAVED.iter().all(|r| callee_saved.contains(r)));
+        assert!(REGISTERS.iter().all(|r| SAVED.contains(r)));
+        assert!(SAVED.contains(&CONTEXT));
+        // The top of the stack.
+        assert!(SAVED.contains(&R15));
+    }
+
+    #[test]
+    fn test_aligned() {
+        let mut a = Assembler::new();
+        enter(&mut a);
+        call_misalignment(&mut a);
+        leave(&mut a);
+        assert_eq!(load(&a.finish().unwrap()).unwrap().execute(&mut Context::new()), 0);
+    }
+
+    #[test]
+    fn test_aligned_in_subroutine() {
+        // Clobbers every saved register, then calls two subroutines deep the
+        // way `Command::Call` does, and leaves from inside them.
+        let mut a = Assembler::new();
+        let (first, second) = (a.new_label(), a.new_label());
+        enter(&mut a);
+        for &r in &SAVED {
+            a.mov_imm(r, 0);
+        }
+        a.sub_imm(Rsp, 8);
+        a.call(first);
+        a.bind(first);
+        a.sub_imm(Rsp, 8);
+        a.call(second);
+        a.bind(second);
+        call_misalignment(&mut a);
+        leave(&mut a);
+        assert_eq!(load(&a.finish().unwrap()).unwrap().execute(&mut Context::new()), 0);
+    }
+
+    #[test]
+    fn test_aligned_in_helpers() {
+        // A subroutine which pushes enough to grow the stack, then prints.
+        let l = Label::Name(vec![true]);
+        let mut program = vec![Command::Call(l.clone()), Command::Exit, Command::Mark(l)];
+        program.extend((0..100).map(Command::Push));
+        program.extend(vec![Command::OutputNum, Command::Return]);
+        let mut context = Context::new();
+        context.capture_stdout(Rc::new(RefCell::new(Vec::new())));
+        let (code, _) = link(program, &mut context, false);
+        let function = load(&code).unwrap();
+
+        let mut table = helpers::table();
+        table[Helper::Print.offset() as usize / 8] = print as *const () as usize;
+        table[Helper::GrowNative.offset() as usize / 8] = grow_native as *const () as usize;
+        let f: extern "C" fn(&mut Context, *const usize) -> i64 = unsafe {
+            transmute(function.address())
+        };
+        f(&mut context, table.as_ptr());
+
+        let checked = CHECKED.with(|checked| checked.borrow().clone());
+        assert!(checked.contains(&(Helper::GrowNative, 0)));
+        assert!(checked.contains(&(Helper::Print, 0)));
+        assert!(checked.iter().all(|&(_, misalignment)| misalignment == 0),
+                "{:?}",
+                checked);
+        assert_eq!(context.stack.len(), 100);
+    }
+}
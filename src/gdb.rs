@@ -0,0 +1,414 @@
+//! Tells GDB about generated code, through the interface it has for JITs
+//! (see "JIT Compilation Interface" in its manual). GDB puts a breakpoint on
+//! `__jit_debug_register_code`, and each time it's hit reads an object file
+//! out of our memory, from the list `__jit_debug_descriptor` keeps. Ours name
+//! each block of the program and say how to unwind through it, so that
+//! breakpoints and backtraces in generated code make sense.
+
+use std::cell::UnsafeCell;
+use std::ops::Range;
+use std::ptr;
+use std::sync::Mutex;
+
+use asm::Assembler;
+use asm::Reg::*;
+use command::{Command, SourceMap};
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER: u32 = 1;
+const JIT_UNREGISTER: u32 = 2;
+
+/// An object file in the list GDB reads.
+#[repr(C)]
+struct Entry {
+    next: *mut Entry,
+    prev: *mut Entry,
+    object: *const u8,
+    size: u64,
+}
+
+/// The head of the list, and what just changed in it.
+#[repr(C)]
+struct Descriptor {
+    version: u32,
+    action: u32,
+    relevant: *mut Entry,
+    first: *mut Entry,
+}
+
+/// The descriptor, which is only touched with `LOCK` held.
+#[repr(transparent)]
+struct Shared(UnsafeCell<Descriptor>);
+
+unsafe impl Sync for Shared {}
+
+// GDB finds these by name, so they're named as it expects.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static __jit_debug_descriptor: Shared = Shared(UnsafeCell::new(Descriptor {
+    version: 1,
+    action: JIT_NOACTION,
+    relevant: ptr::null_mut(),
+    first: ptr::null_mut(),
+}));
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Where GDB stops to read the list. It does nothing, but has to be called,
+/// so it mustn't be inlined or optimized away.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    unsafe { ::std::arch::asm!("", options(nomem, nostack)) }
+}
+
+/// Code GDB knows about, until this is dropped. The code mustn't move or go
+/// away before then, so nothing more can be loaded into its arena.
+pub struct Registration {
+    entry: Box<Entry>,
+    // Where `entry` points.
+    _object: Vec<u8>,
+}
+
+/// Tells GDB about `len` bytes of code at `address`, which was linked with
+/// `map` and has the given symbols, as from `perf::symbols`.
+pub fn register(address: usize,
+                len: usize,
+                map: &SourceMap,
+                symbols: &[(Range<usize>, String)])
+                -> Registration {
+    // The code leaves through the end of `Command::Deinitialize`.
+    let leave = map.iter()
+                   .find(|(command, _)| *command == Command::Deinitialize)
+                   .map(|(_, range)| range.end);
+    let object = object(address, len, leave, symbols);
+    let mut entry = Box::new(Entry {
+        next: ptr::null_mut(),
+        prev: ptr::null_mut(),
+        object: object.as_ptr(),
+        size: object.len() as u64,
+    });
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    unsafe {
+        let descriptor = &mut *__jit_debug_descriptor.0.get();
+        entry.next = descriptor.first;
+        if !entry.next.is_null() {
+            (*entry.next).prev = &mut *entry;
+        }
+        descriptor.first = &mut *entry;
+        descriptor.relevant = &mut *entry;
+        descriptor.action = JIT_REGISTER;
+        __jit_debug_register_code();
+    }
+    Registration {
+        entry,
+        _object: object,
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let descriptor = &mut *__jit_debug_descriptor.0.get();
+            let (prev, next) = (self.entry.prev, self.entry.next);
+            if prev.is_null() {
+                descriptor.first = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            descriptor.relevant = &mut *self.entry;
+            descriptor.action = JIT_UNREGISTER;
+            __jit_debug_register_code();
+        }
+    }
+}
+
+/// A section of the object file.
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    // Of the section in memory, which for `.text` isn't in the file.
+    size: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    entry_size: u64,
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+
+/// An ELF executable for `len` bytes of code at `address`, with nothing in
+/// it but symbols and how to unwind. Being an executable rather than a
+/// relocatable file, every address in it is absolute.
+fn object(address: usize,
+          len: usize,
+          leave: Option<usize>,
+          symbols: &[(Range<usize>, String)])
+          -> Vec<u8> {
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    for (range, name) in symbols {
+        symtab.extend(&(strtab.len() as u32).to_le_bytes());
+        // A global function in `.text`.
+        symtab.extend(&[0x12, 0]);
+        symtab.extend(&1u16.to_le_bytes());
+        symtab.extend(&((address + range.start) as u64).to_le_bytes());
+        symtab.extend(&(range.len() as u64).to_le_bytes());
+        strtab.extend(name.as_bytes());
+        strtab.push(0);
+    }
+
+    let section = |name, kind, data: Vec<u8>| Section {
+        name,
+        kind,
+        flags: 0,
+        address: 0,
+        size: data.len() as u64,
+        data,
+        link: 0,
+        info: 0,
+        entry_size: 0,
+    };
+    let mut sections = vec![section("", 0, vec![]),
+                            Section {
+                                flags: 6, // Allocated and executable.
+                                address: address as u64,
+                                size: len as u64,
+                                ..section(".text", SHT_NOBITS, vec![])
+                            },
+                            Section {
+                                link: 3,
+                                info: 1, // The first global symbol.
+                                entry_size: 24,
+                                ..section(".symtab", SHT_SYMTAB, symtab)
+                            },
+                            section(".strtab", SHT_STRTAB, strtab),
+                            section(".debug_frame", SHT_PROGBITS, debug_frame(address, len, leave))];
+    let mut shstrtab = vec![0];
+    let mut names = vec![];
+    for section in &sections {
+        names.push(shstrtab.len() as u32);
+        shstrtab.extend(section.name.as_bytes());
+        shstrtab.push(0);
+    }
+    names.push(shstrtab.len() as u32);
+    shstrtab.extend(b".shstrtab\0");
+    sections.push(section(".shstrtab", SHT_STRTAB, shstrtab));
+
+    // The header, then each section, then the section headers.
+    let mut out = vec![0; 64];
+    let mut offsets = vec![];
+    for section in &sections {
+        out.resize((out.len() + 7) & !7, 0);
+        offsets.push(out.len() as u64);
+        out.extend(&section.data);
+    }
+    out.resize((out.len() + 7) & !7, 0);
+    let headers = out.len() as u64;
+    for ((section, name), offset) in sections.iter().zip(names).zip(offsets) {
+        out.extend(&name.to_le_bytes());
+        out.extend(&section.kind.to_le_bytes());
+        out.extend(&section.flags.to_le_bytes());
+        out.extend(&section.address.to_le_bytes());
+        out.extend(&offset.to_le_bytes());
+        out.extend(&section.size.to_le_bytes());
+        out.extend(&section.link.to_le_bytes());
+        out.extend(&section.info.to_le_bytes());
+        out.extend(&8u64.to_le_bytes());
+        out.extend(&section.entry_size.to_le_bytes());
+    }
+
+    let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    header.extend(&2u16.to_le_bytes()); // An executable,
+    header.extend(&62u16.to_le_bytes()); // for x86-64.
+    header.extend(&1u32.to_le_bytes());
+    header.extend(&0u64.to_le_bytes());
+    header.extend(&0u64.to_le_bytes());
+    header.extend(&headers.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    header.extend(&64u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&64u16.to_le_bytes());
+    header.extend(&(sections.len() as u16).to_le_bytes());
+    header.extend(&(sections.len() as u16 - 1).to_le_bytes());
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+/// DWARF call frame information for the code: once `frame::enter` has set
+/// up `rbp`, the return address is just above it wherever we are, even in
+/// the program's own subroutines, until `frame::leave` pops it again just
+/// before `leave`.
+fn debug_frame(address: usize, len: usize, leave: Option<usize>) -> Vec<u8> {
+    /// Adds an entry, padded to a multiple of the address size with
+    /// `DW_CFA_nop`s.
+    fn entry(out: &mut Vec<u8>, mut body: Vec<u8>) {
+        body.resize(((4 + body.len() + 7) & !7) - 4, 0);
+        out.extend(&(body.len() as u32).to_le_bytes());
+        out.extend(body);
+    }
+
+    let mut a = Assembler::new();
+    a.push(Rbp);
+    let pushed = a.len();
+    a.mov(Rbp, Rsp);
+    let moved = a.len();
+    a.ret();
+    let ret = a.len() - moved;
+
+    let mut out = vec![];
+    // The CIE: version 1, no augmentation, code alignment 1, data alignment
+    // -8 and the return address in register 16. On entry, the CFA is `rsp`
+    // + 8 and the return address is just below it.
+    let mut cie = vec![0xff, 0xff, 0xff, 0xff, 1, 0, 1, 0x78, 16];
+    cie.extend(&[0x0c, 7, 8, 0x80 | 16, 1]);
+    entry(&mut out, cie);
+
+    // The FDE for all the code, which refers to the CIE.
+    let mut fde = 0u32.to_le_bytes().to_vec();
+    fde.extend(&(address as u64).to_le_bytes());
+    fde.extend(&(len as u64).to_le_bytes());
+    // After `push rbp`, the CFA is `rsp` + 16 and `rbp` is below the return
+    // address. After `mov rbp, rsp`, the CFA is `rbp` + 16.
+    fde.extend(&[0x40 | pushed as u8, 0x0e, 16, 0x80 | 6, 2]);
+    fde.extend(&[0x40 | (moved - pushed) as u8, 0x0d, 6]);
+    // At `frame::leave`'s `ret`, `rbp` has been popped, so the CFA is `rsp`
+    // + 8 again. Whatever follows it, like the stubs, runs in the frame.
+    if let Some(leave) = leave {
+        fde.push(0x04);
+        fde.extend(&((leave - ret - moved) as u32).to_le_bytes());
+        fde.extend(&[0x0a, 0x0c, 7, 8, 0xc0 | 6]);
+        fde.extend(&[0x40 | ret as u8, 0x0b]);
+    }
+    entry(&mut out, fde);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[at..at + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn name_at(data: &[u8], at: usize) -> String {
+        let end = data[at..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(data[at..at + end].to_vec()).unwrap()
+    }
+
+    /// The name, address, size and contents of each section of an object.
+    fn sections(object: &[u8]) -> Vec<(String, u64, u64, &[u8])> {
+        let (headers, count) = (u64_at(object, 0x28) as usize, u16_at(object, 0x3c) as usize);
+        let header = |i: usize| headers + 64 * i;
+        let names = header(u16_at(object, 0x3e) as usize);
+        let names = &object[u64_at(object, names + 24) as usize..];
+        (0..count).map(|i| {
+                      let at = header(i);
+                      let (offset, size) = (u64_at(object, at + 24), u64_at(object, at + 32));
+                      let data = match u32_at(object, at + 4) {
+                          SHT_NOBITS => &[][..],
+                          _ => &object[offset as usize..(offset + size) as usize],
+                      };
+                      (name_at(names, u32_at(object, at) as usize),
+                       u64_at(object, at + 16),
+                       size,
+                       data)
+                  })
+                  .collect()
+    }
+
+    /// Every registered object, newest first.
+    fn registered() -> Vec<Vec<u8>> {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut objects = vec![];
+        unsafe {
+            let mut entry = (*__jit_debug_descriptor.0.get()).first;
+            while !entry.is_null() {
+                objects.push(slice::from_raw_parts((*entry).object, (*entry).size as usize)
+                                 .to_vec());
+                entry = (*entry).next;
+            }
+        }
+        objects
+    }
+
+    #[test]
+    fn test_object() {
+        let symbols = vec![(0..0x20, "a.ws block 0".to_string()),
+                           (0x20..0x30, "a.ws mark 01".to_string())];
+        let object = object(0x1000, 0x40, Some(0x30), &symbols);
+        assert_eq!(&object[..4], b"\x7fELF");
+
+        let sections = sections(&object);
+        let names = sections.iter().map(|s| &s.0[..]).collect::<Vec<_>>();
+        assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".debug_frame", ".shstrtab"]);
+        assert_eq!((sections[1].1, sections[1].2), (0x1000, 0x40));
+
+        let (symtab, strtab) = (sections[2].3, sections[3].3);
+        let symbols = symtab.chunks(24)
+                            .skip(1)
+                            .map(|s| {
+                                (name_at(strtab, u32_at(s, 0) as usize),
+                                 u64_at(s, 8),
+                                 u64_at(s, 16))
+                            })
+                            .collect::<Vec<_>>();
+        assert_eq!(symbols,
+                   [("a.ws block 0".to_string(), 0x1000, 0x20),
+                    ("a.ws mark 01".to_string(), 0x1020, 0x10)]);
+
+        // The CIE and the FDE, which covers all the code.
+        let frame = sections[4].3;
+        let cie = u32_at(frame, 0) as usize + 4;
+        assert_eq!((cie % 8, frame.len() % 8), (0, 0));
+        assert_eq!(u32_at(frame, 4), 0xffff_ffff);
+        assert_eq!(u32_at(frame, cie + 4), 0);
+        assert_eq!((u64_at(frame, cie + 8), u64_at(frame, cie + 16)), (0x1000, 0x40));
+        // The `ret` at 0x2f has a rule of its own.
+        let epilogue = [0x04, 0x2b, 0, 0, 0, 0x0a, 0x0c, 7, 8, 0xc6, 0x41, 0x0b];
+        assert!(frame.windows(epilogue.len()).any(|w| w == epilogue));
+    }
+
+    #[test]
+    fn test_register() {
+        let symbols = vec![(0..0x10, "b.ws block 0".to_string())];
+        let map = vec![(Command::Deinitialize, 0..0x10)];
+        let (first, second) = (object(0x2000, 0x10, Some(0x10), &symbols),
+                               object(0x3000, 0x10, Some(0x10), &symbols));
+        let registration = register(0x2000, 0x10, &map, &symbols);
+        assert!(registered().contains(&first));
+        {
+            let _second = register(0x3000, 0x10, &map, &symbols);
+            let objects = registered();
+            let position = |object| objects.iter().position(|o| o == object).unwrap();
+            assert!(position(&second) < position(&first));
+        }
+        assert!(!registered().contains(&second));
+        drop(registration);
+        assert!(!registered().contains(&first));
+    }
+}
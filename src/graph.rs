@@ -0,0 +1,166 @@
+use std::fmt::Write;
+
+use cfg::{BlockId, Cfg, Edge};
+use depth::StackDepths;
+
+/// Escapes a string for use inside a quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Quotes a string for use as a DOT identifier or label.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+/// What to call a block: its label if it has one, otherwise its number.
+fn block_name(cfg: &Cfg, id: BlockId) -> String {
+    match cfg.blocks[id].label {
+        Some(ref label) => label.to_string(),
+        None => format!("block {}", id),
+    }
+}
+
+/// What to call a subroutine, by its first block.
+fn subroutine_name(cfg: &Cfg, id: BlockId) -> String {
+    if id == 0 {
+        "entry".to_string()
+    } else {
+        block_name(cfg, id)
+    }
+}
+
+fn edge_name(edge: Edge) -> &'static str {
+    match edge {
+        Edge::Taken => "taken",
+        Edge::Fallthrough => "fallthrough",
+        Edge::Call => "call",
+    }
+}
+
+/// The control-flow graph as DOT, with a node for each basic block showing
+/// its disassembly. Blocks which can never run are greyed out, and edges
+/// which go back round a loop are drawn bold.
+pub fn cfg_dot(cfg: &Cfg) -> String {
+    let reachable = cfg.reachable();
+    let idom = cfg.dominators();
+    let mut out = String::new();
+    out.push_str("digraph cfg {\n");
+    out.push_str("    node [shape=box, fontname=monospace];\n");
+    for (id, &reachable) in reachable.iter().enumerate() {
+        // \l left-justifies each line.
+        let mut label = String::new();
+        for command in cfg.commands(id) {
+            write!(label, "{}\\l", escape(&command.to_string())).unwrap();
+        }
+        write!(out, "    b{} [label=\"{}\"", id, label).unwrap();
+        if !reachable {
+            out.push_str(", color=grey, fontcolor=grey");
+        }
+        out.push_str("];\n");
+    }
+    for (id, successors) in cfg.successors.iter().enumerate() {
+        for &(to, edge) in successors {
+            write!(out, "    b{} -> b{} [label={}", id, to, quote(edge_name(edge))).unwrap();
+            if edge == Edge::Call {
+                out.push_str(", style=dashed");
+            } else if idom[id].is_some() && cfg.dominates(&idom, to, id) {
+                // Going back to a block every way here went through.
+                out.push_str(", style=bold");
+            }
+            out.push_str("];\n");
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// The call graph as DOT, with a node for each subroutine which can be
+/// reached from the entry point, showing how it changes the stack.
+pub fn call_graph_dot(cfg: &Cfg) -> String {
+    let graph = cfg.call_graph();
+    let depths = StackDepths::of(cfg.program);
+    let mut out = String::new();
+    out.push_str("digraph calls {\n");
+    for &id in graph.calls.keys() {
+        let mut name = subroutine_name(cfg, id);
+        if let Some(ref label) = cfg.blocks[id].label {
+            if let Some(signature) = depths.signatures.get(label) {
+                write!(name, "\n{}", signature).unwrap();
+            }
+        }
+        writeln!(out, "    b{} [label={}];", id, quote(&name)).unwrap();
+    }
+    for (&from, callees) in &graph.calls {
+        for &to in callees {
+            writeln!(out, "    b{} -> b{} [label=\"call\"];", from, to).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::Command;
+    use l;
+
+    fn program() -> Vec<Command> {
+        vec![Command::Push(1),
+             Command::JumpZero(l("0")),
+             Command::Call(l("1")),
+             Command::Mark(l("0")),
+             Command::Exit,
+             Command::Mark(l("1")),
+             Command::Return]
+    }
+
+    #[test]
+    fn test_cfg_dot() {
+        let program = program();
+        assert_eq!(cfg_dot(&Cfg::new(&program)),
+                   "digraph cfg {\n\
+                    \x20   node [shape=box, fontname=monospace];\n\
+                    \x20   b0 [label=\"push 1\\ljz 0\\l\"];\n\
+                    \x20   b1 [label=\"call 1\\l\"];\n\
+                    \x20   b2 [label=\"mark 0\\lexit\\l\"];\n\
+                    \x20   b3 [label=\"mark 1\\lret\\l\"];\n\
+                    \x20   b0 -> b2 [label=\"taken\"];\n\
+                    \x20   b0 -> b1 [label=\"fallthrough\"];\n\
+                    \x20   b1 -> b3 [label=\"call\", style=dashed];\n\
+                    \x20   b1 -> b2 [label=\"fallthrough\"];\n\
+                    }\n");
+    }
+
+    #[test]
+    fn test_unreachable_greyed_out() {
+        let program = vec![Command::Exit, Command::Push(1)];
+        let dot = cfg_dot(&Cfg::new(&program));
+        assert!(dot.contains("b1 [label=\"push 1\\l\", color=grey, fontcolor=grey];"));
+        assert!(!dot.contains("b0 [label=\"exit\\l\", color"));
+    }
+
+    #[test]
+    fn test_loops_bold() {
+        let program = vec![Command::Mark(l("0")),
+                           Command::Push(1),
+                           Command::JumpZero(l("0")),
+                           Command::Jump(l("0"))];
+        let dot = cfg_dot(&Cfg::new(&program));
+        assert!(dot.contains("b0 -> b0 [label=\"taken\", style=bold];"));
+        assert!(dot.contains("b0 -> b1 [label=\"fallthrough\"];"));
+        assert!(dot.contains("b1 -> b0 [label=\"taken\", style=bold];"));
+    }
+
+    #[test]
+    fn test_call_graph_dot() {
+        let program = program();
+        assert_eq!(call_graph_dot(&Cfg::new(&program)),
+                   "digraph calls {\n\
+                    \x20   b0 [label=\"entry\"];\n\
+                    \x20   b3 [label=\"1\\n0 -> 0\"];\n\
+                    \x20   b0 -> b3 [label=\"call\"];\n\
+                    }\n");
+    }
+}
@@ -0,0 +1,143 @@
+//! The Whitespace heap, as the generated code sees it. Addresses in the dense
+//! part of `Context::heap` are read and written inline; anything else goes
+//! through the context.
+
+use asm::{Assembler, Cond, Label, Mem};
+use asm::Reg::*;
+use stack;
+
+/// Jumps to `slow` unless the address in `rax` is in the dense part, which
+/// also rules out negative addresses. Otherwise leaves a pointer to its slot
+/// in `rax`.
+fn slot(a: &mut Assembler, slow: Label) {
+    a.cmp_mem(Rax, field!(heap.native_len));
+    a.jcc_short(Cond::Ae, slow);
+    a.shl_imm(Rax, 4);
+    a.add_mem(Rax, field!(heap.native_base));
+}
+
+/// Stores the item on top of the stack at the address below it.
+pub fn store(a: &mut Assembler) {
+    let (slow, done) = (a.new_label(), a.new_label());
+    stack::peek(a, 0);
+    a.mov(Rbx, Rax);
+    stack::peek(a, 1);
+    slot(a, slow);
+    a.store(Mem::new(Rax, 0), Rbx);
+    // present
+    a.store_byte(Mem::new(Rax, 8), 1);
+    a.jmp_short(done);
+
+    a.bind(slow);
+    stack::sync(a);
+    fn_call!(a, Store);
+    a.bind(done);
+}
+
+/// Reads the value at the address on top of the stack into `rax`.
+pub fn retrieve(a: &mut Assembler) {
+    let (slow, done) = (a.new_label(), a.new_label());
+    stack::peek(a, 0);
+    slot(a, slow);
+    // nothing stored here yet
+    a.cmp_byte(Mem::new(Rax, 8), 0);
+    a.jcc_short(Cond::E, slow);
+    a.load(Rax, Mem::new(Rax, 0));
+    a.jmp_short(done);
+
+    a.bind(slow);
+    stack::sync(a);
+    fn_call!(a, Retrieve);
+    a.bind(done);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use command::Command;
+    use run_captured;
+    use wsstd::Heap;
+    use Number;
+
+    #[test]
+    fn test_matches_map() {
+        let mut heap = Heap::new();
+        let mut map = HashMap::new();
+        let addresses = [0, 1, 5, 15, 16, 17, 100, 4095, -1, -100,
+                         Heap::DENSE_LIMIT - 1, Heap::DENSE_LIMIT, Number::MAX, Number::MIN];
+        // A simple LCG, so the order is mixed up but the same every time.
+        let mut seed: u64 = 1;
+        for value in 0..1000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let address = addresses[(seed >> 33) as usize % addresses.len()];
+            assert_eq!(heap.get(address), map.get(&address).cloned());
+            heap.insert(address, value);
+            map.insert(address, value);
+        }
+        assert_eq!(heap, map);
+        for &address in &addresses {
+            assert_eq!(heap.get(address), map.get(&address).cloned());
+        }
+        assert_eq!(heap.get(2), None);
+        assert_eq!(heap.to_map(), map);
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut program = vec![];
+        let addresses = [3, 0, -7, 1000, Heap::DENSE_LIMIT, 3];
+        for (i, &address) in addresses.iter().enumerate() {
+            program.extend(vec![Command::Push(address),
+                                Command::Push(i as Number * 10),
+                                Command::Store,
+                                Command::Pop,
+                                Command::Retrieve,
+                                Command::OutputNum,
+                                Command::Pop,
+                                Command::Pop]);
+        }
+        program.push(Command::Exit);
+        let outcome = run_captured(program, b"");
+        assert_eq!(outcome.stdout, b"01020304050".to_vec());
+        assert!(outcome.stack.is_empty());
+
+        let mut heap = HashMap::new();
+        heap.insert(0, 10);
+        heap.insert(-7, 20);
+        heap.insert(1000, 30);
+        heap.insert(Heap::DENSE_LIMIT, 40);
+        heap.insert(3, 50);
+        assert_eq!(outcome.heap, heap);
+    }
+
+    #[test]
+    fn test_read_then_retrieve() {
+        // The context grows the dense part, and the generated code sees it.
+        let program = vec![Command::Push(5),
+                           Command::ReadNum,
+                           Command::Retrieve,
+                           Command::OutputNum,
+                           Command::Exit];
+        let outcome = run_captured(program, b"42\n");
+        assert_eq!(outcome.stdout, b"42".to_vec());
+        assert_eq!(outcome.stack, vec![5, 42]);
+    }
+
+    #[test]
+    fn test_grows_while_full() {
+        let mut heap = Heap::new();
+        heap.insert(Heap::DENSE_LIMIT - 1, 1);
+        heap.insert(100, 2);
+        assert_eq!(heap.native_len, 0);
+
+        // Filling in from the bottom grows it, taking in what was past it.
+        for address in 0..65 {
+            heap.insert(address, address);
+        }
+        assert_eq!(heap.native_len, 128);
+        assert_eq!(heap.get(100), Some(2));
+        assert_eq!(heap.get(Heap::DENSE_LIMIT - 1), Some(1));
+        assert_eq!(heap.len(), 67);
+    }
+}
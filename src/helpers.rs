@@ -0,0 +1,86 @@
+//! The context's methods which generated code calls. It's given a table of
+//! their addresses when it's called, so the code itself doesn't depend on
+//! where anything was loaded.
+
+use wsstd::Context;
+
+macro_rules! helpers {
+    ($($(#[$attr:meta])* $helper:ident => $method:ident,)*) => {
+        /// A method of the context which generated code can call.
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub enum Helper {
+            $($(#[$attr])* $helper,)*
+        }
+
+        /// The address and name of each helper, in order.
+        // Some of them may be configured out, which `vec!` can't do.
+        #[allow(clippy::vec_init_then_push)]
+        fn helpers() -> Vec<(usize, &'static str)> {
+            let mut helpers = vec![];
+            $(
+                $(#[$attr])*
+                helpers.push((Context::$method as *const () as usize,
+                              concat!("Context::", stringify!($method))));
+            )*
+            helpers
+        }
+    }
+}
+
+helpers! {
+    SlideStack => slide_stack,
+    Store => store,
+    Retrieve => retrieve,
+    Print => print,
+    Read => read,
+    EnterNative => enter_native,
+    SyncNative => sync_native,
+    GrowNative => grow_native,
+    NativeUnderflow => native_underflow,
+    #[cfg(feature = "extensions")]
+    DumpStack => dump_stack,
+    #[cfg(feature = "extensions")]
+    DumpHeap => dump_heap,
+    #[cfg(feature = "extensions")]
+    SetTrace => set_trace,
+    #[cfg(feature = "extensions")]
+    Trace => trace,
+    #[cfg(feature = "extensions")]
+    Breakpoint => breakpoint,
+}
+
+impl Helper {
+    /// Where this helper's address is in the table.
+    pub fn offset(self) -> i32 {
+        self as i32 * 8
+    }
+}
+
+/// The table generated code is called with.
+pub fn table() -> Vec<usize> {
+    helpers().into_iter().map(|(address, _)| address).collect()
+}
+
+/// What to call the helper at `offset` in the table.
+pub fn name(offset: i32) -> Option<&'static str> {
+    if offset % 8 != 0 {
+        return None;
+    }
+    helpers().get((offset / 8) as usize).map(|&(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let table = table();
+        assert_eq!(table[Helper::Print.offset() as usize / 8], Context::print as *const () as usize);
+        assert_eq!(table[Helper::NativeUnderflow.offset() as usize / 8],
+                   Context::native_underflow as *const () as usize);
+        assert_eq!(name(Helper::Read.offset()), Some("Context::read"));
+        assert_eq!(name(Helper::Read.offset() + 4), None);
+        assert_eq!(name(table.len() as i32 * 8), None);
+    }
+}
@@ -1,127 +1,340 @@
-
 use libc as c;
-use memmap2::{Mmap, MmapMut};
+use memmap2::MmapMut;
+use std::cell::RefCell;
+use std::io;
 use std::mem::transmute;
 use std::ops::{Index, IndexMut};
-use std::marker::PhantomData;
+use std::rc::Rc;
 
+use fault::{self, Fault};
+use helpers;
+use Number;
 use wsstd::Context;
 
-pub struct JitMemory<'a> {
+/// Memory for machine code, which grows as code is appended to it. It's
+/// either writable or executable, never both, and flipping between the two
+/// keeps what's already there. There's an inaccessible guard page on either
+/// side, so that running or writing off either end faults.
+pub struct JitMemory {
+    // Including the guard pages.
     contents: MmapMut,
-    size: usize,
-    phantom: PhantomData<&'a mut Context>,
-}
-
-pub struct JitFunction<'a> {
-    contents: Mmap,
-    size: usize,
-    phantom: PhantomData<&'a mut Context>,
+    // How much of it has been used.
+    len: usize,
+    executable: bool,
 }
 
-impl<'a> JitMemory<'a> {
-
+impl JitMemory {
     pub fn get_page_size() -> usize {
         unsafe { c::sysconf(c::_SC_PAGESIZE) as usize }
     }
 
-    pub fn new(num_pages: usize) -> Self {
+    /// Maps some writable pages, or fails if the system won't give us any.
+    pub fn new(num_pages: usize) -> io::Result<Self> {
         let page_size = JitMemory::get_page_size();
-        let size = num_pages * page_size;
+        let size = num_pages.max(1) * page_size;
 
-        // Let's allocate space for the JIT function.
-        let mut page = MmapMut::map_anon(size).unwrap();
+        let mut memory = JitMemory {
+            contents: MmapMut::map_anon(size + 2 * page_size)?,
+            len: 0,
+            executable: false,
+        };
         // Fill it with "int" (0xCC) to avoid using uninitialized memory.
-        page.fill(0xcc);
-
-        JitMemory {
-            contents: page,
-            size: size,
-            phantom: PhantomData,
+        memory.code_mut().fill(0xcc);
+        for guard in [0, page_size + size] {
+            protect(&mut memory.contents[guard..guard + page_size], c::PROT_NONE)?;
         }
+        Ok(memory)
+    }
+
+    /// The memory between the guard pages.
+    fn code(&self) -> &[u8] {
+        let page_size = JitMemory::get_page_size();
+        &self.contents[page_size..self.contents.len() - page_size]
+    }
+
+    fn code_mut(&mut self) -> &mut [u8] {
+        let page_size = JitMemory::get_page_size();
+        let end = self.contents.len() - page_size;
+        &mut self.contents[page_size..end]
+    }
+
+    /// How much code has been appended.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// How much code fits before it has to grow.
+    pub fn capacity(&self) -> usize {
+        self.code().len()
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.executable
     }
 
-    /// Copies a slice into memory
-    pub fn copy_from(&mut self, data: &[u8]) {
-        for (i, byte) in data.iter().enumerate() {
-            self[i] = *byte;
+    /// Adds code after what's already there, mapping more pages if it doesn't
+    /// fit, and returns where it went. Growing moves everything, so code has
+    /// to be referred to by offset rather than address.
+    pub fn append(&mut self, code: &[u8]) -> io::Result<usize> {
+        assert!(!self.executable, "appending to executable JitMemory");
+        let offset = self.len;
+        if offset + code.len() > self.capacity() {
+            let pages = (offset + code.len()).max(2 * self.capacity()) /
+                        JitMemory::get_page_size() + 1;
+            let mut grown = JitMemory::new(pages)?;
+            grown.code_mut()[..offset].copy_from_slice(&self.code()[..offset]);
+            self.contents = grown.contents;
         }
+        self.code_mut()[offset..offset + code.len()].copy_from_slice(code);
+        self.len += code.len();
+        Ok(offset)
+    }
+
+    /// Overwrites some code which has already been appended. Only the tests
+    /// patch code so far.
+    #[cfg(test)]
+    pub fn patch(&mut self, offset: usize, code: &[u8]) {
+        assert!(!self.executable, "patching executable JitMemory");
+        assert!(offset + code.len() <= self.len,
+                "patch at {} out of bounds for JitMemory",
+                offset);
+        self.code_mut()[offset..offset + code.len()].copy_from_slice(code);
+    }
+
+    /// Makes the code executable, but not writable.
+    pub fn make_exec(&mut self) -> io::Result<()> {
+        protect(self.code_mut(), c::PROT_READ | c::PROT_EXEC)?;
+        self.executable = true;
+        Ok(())
+    }
+
+    /// Makes the code writable, but not executable.
+    pub fn make_mut(&mut self) -> io::Result<()> {
+        protect(self.code_mut(), c::PROT_READ | c::PROT_WRITE)?;
+        self.executable = false;
+        Ok(())
     }
 }
 
-impl<'a> JitFunction<'a> {
-    pub fn execute(self) -> i64 {
-        let f: fn() -> i64 = unsafe {
-            transmute(self.contents.as_ptr())
-        };
-        f()
+/// Changes what can be done with some whole pages.
+fn protect(pages: &mut [u8], protection: c::c_int) -> io::Result<()> {
+    let address = pages.as_mut_ptr() as *mut c::c_void;
+    if unsafe { c::mprotect(address, pages.len(), protection) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
 }
 
-impl<'a> Into<JitFunction<'a>> for JitMemory<'a> {
-    fn into(self) -> JitFunction<'a> {
-        // Mark the function as executable, but not writable.
-        JitFunction {
-            contents: self.contents.make_exec().unwrap(),
-            size: self.size,
-            phantom: PhantomData,
-        }
+/// Memory which several functions can be loaded into.
+#[derive(Clone)]
+pub struct Arena(Rc<RefCell<JitMemory>>);
+
+impl Arena {
+    pub fn new(num_pages: usize) -> io::Result<Self> {
+        Ok(Arena(Rc::new(RefCell::new(JitMemory::new(num_pages)?))))
+    }
+
+    /// Appends some code, which is executable once this returns, along with
+    /// everything else in the arena.
+    pub fn load(&self, code: &[u8]) -> io::Result<JitFunction> {
+        let mut memory = self.0.borrow_mut();
+        memory.make_mut()?;
+        // Whatever was already there has to be runnable again, even if this
+        // can't be.
+        let appended = memory.append(code);
+        memory.make_exec()?;
+        Ok(JitFunction {
+            arena: self.clone(),
+            offset: appended?,
+            len: code.len(),
+        })
     }
 }
 
-impl<'a> Into<JitMemory<'a>> for JitFunction<'a> {
-    fn into(self) -> JitMemory<'a> {
-        // Mark the function as writable, but not executable.
-        JitMemory {
-            contents: self.contents.make_mut().unwrap(),
-            size: self.size,
-            phantom: PhantomData,
+/// Compiled code, which can be run any number of times, against any context.
+pub struct JitFunction {
+    arena: Arena,
+    offset: usize,
+    len: usize,
+}
+
+impl JitFunction {
+    /// Where the code is now. Loading more into the arena can move it.
+    pub fn address(&self) -> usize {
+        self.arena.0.borrow().code().as_ptr() as usize + self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Runs the code, passing it the context and the table of helpers.
+    pub fn execute(&self, context: &mut Context) -> i64 {
+        let memory = self.arena.0.borrow();
+        assert!(memory.is_executable());
+        let f: extern "C" fn(&mut Context, *const usize) -> i64 = unsafe {
+            transmute(memory.code().as_ptr().add(self.offset))
+        };
+        f(context, helpers::table().as_ptr())
+    }
+
+    /// Runs the code, but if it faults, returns where rather than dying. The
+    /// context's stack is whatever the code had made it by then.
+    pub fn execute_guarded(&self, context: &mut Context) -> Result<i64, Fault> {
+        let result = {
+            let memory = self.arena.0.borrow();
+            let code = &memory.code()[self.offset..self.offset + self.len];
+            fault::guard(code, || self.execute(context))
+        };
+        if let Err(fault) = result {
+            let top = fault.top as *mut Number;
+            if context.native_base <= top && top <= context.native_limit {
+                unsafe { context.sync_native(top) };
+            }
         }
+        result
     }
 }
 
-impl<'a> Index<usize> for JitMemory<'a> {
+impl Index<usize> for JitMemory {
     type Output = u8;
 
-    fn index(&self, _index: usize) -> &u8 {
-        if _index > self.size {
-            panic!("index {} out of bounds for JitMemory", _index);
+    fn index(&self, index: usize) -> &u8 {
+        if index >= self.capacity() {
+            panic!("index {} out of bounds for JitMemory", index);
         }
-        &self.contents[_index]
+        &self.code()[index]
     }
 }
 
-impl<'a> IndexMut<usize> for JitMemory<'a> {
-    fn index_mut(&mut self, _index: usize) -> &mut u8 {
-        if _index > self.size {
-            panic!("index {} out of bounds for JitMemory", _index);
+impl IndexMut<usize> for JitMemory {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        if index >= self.capacity() || self.executable {
+            panic!("index {} out of bounds for writable JitMemory", index);
         }
-        &mut self.contents[_index]
+        &mut self.code_mut()[index]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use asm::Assembler;
+    use asm::Reg::*;
+
+    /// Code which returns `n`.
+    fn returns(n: u64) -> Vec<u8> {
+        let mut a = Assembler::new();
+        a.mov_imm(Rax, n);
+        a.ret();
+        a.finish().unwrap()
+    }
 
     fn check_output(program: &[u8], output: i64) {
-        let mut memory = JitMemory::new(program.len() / JitMemory::get_page_size() + 1);
+        let function = Arena::new(0).unwrap().load(program).unwrap();
+        assert_eq!(output, function.execute(&mut Context::new()));
+    }
 
-        memory.copy_from(program);
+    #[test]
+    fn jit() {
+        let mut a = Assembler::new();
+        a.mov_imm(Rax, 0x20);
+        a.add_imm(Rax, 0x0a);
+        a.sub_imm(Rax, 0x0a);
+        a.ret();
+        check_output(&a.finish().unwrap(), 32);
 
-        let function: JitFunction = memory.into();
-        assert_eq!(output, function.execute());
+        let mut a = Assembler::new();
+        a.mov_imm(Rax, 0x20);
+        a.add_imm(Rax, 0x0a);
+        a.ret();
+        check_output(&a.finish().unwrap(), 42);
     }
 
     #[test]
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    fn jit() {
-        check_output(&[0x48, 0xC7, 0xC0, 0x20, 0x00, 0x00, 0x00,    // mov rax, 0x20
-                       0x48, 0x83, 0xC0, 0x0A,                      // add rax, 0x0A
-                       0x48, 0x83, 0xE8, 0x0A,                      // sub rax, 0x0A
-                       0xC3], 32);                                  // ret
-        check_output(&[0x48, 0xC7, 0xC0, 0x20, 0x00, 0x00, 0x00,    // mov rax, 0x20
-                       0x48, 0x83, 0xC0, 0x0A,                      // add rax, 0x0A
-                       0xC3], 42);                                  // ret
+    #[should_panic(expected = "out of bounds")]
+    fn test_bounds() {
+        let memory = JitMemory::new(1).unwrap();
+        let _ = memory[memory.capacity()];
+    }
+
+    /// The permissions of the mapping `address` is in, as `/proc` shows them.
+    fn permissions(address: *const u8) -> String {
+        let maps = ::std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let range = fields[0].split('-')
+                                 .map(|n| usize::from_str_radix(n, 16).unwrap())
+                                 .collect::<Vec<_>>();
+            if (range[0]..range[1]).contains(&(address as usize)) {
+                return fields[1].to_string();
+            }
+        }
+        panic!("{:?} isn't mapped", address);
+    }
+
+    #[test]
+    fn test_protection() {
+        let mut memory = JitMemory::new(2).unwrap();
+        let (start, end) = (memory.code().as_ptr(), memory.code().as_ptr_range().end);
+        let page_size = JitMemory::get_page_size();
+        let guards = unsafe { (start.sub(page_size), end) };
+        let all = || [permissions(guards.0), permissions(start), permissions(guards.1)];
+
+        assert_eq!(all(), ["---p", "rw-p", "---p"]);
+        memory.make_exec().unwrap();
+        assert_eq!(all(), ["---p", "r-xp", "---p"]);
+        memory.make_mut().unwrap();
+        assert_eq!(all(), ["---p", "rw-p", "---p"]);
+    }
+
+    #[test]
+    fn test_append() {
+        let page = JitMemory::get_page_size();
+        let mut memory = JitMemory::new(1).unwrap();
+        assert_eq!(memory.append(&[0x90; 10]).unwrap(), 0);
+        assert_eq!(memory.append(&vec![0xc3; page]).unwrap(), 10);
+        assert_eq!(memory.len(), page + 10);
+        assert!(memory.capacity() >= page + 10);
+        assert_eq!((memory[9], memory[10], memory[page + 9]), (0x90, 0xc3, 0xc3));
+        assert_eq!(memory[page + 10], 0xcc);
+    }
+
+    #[test]
+    fn test_patch() {
+        let arena = Arena::new(1).unwrap();
+        let function = arena.load(&returns(32)).unwrap();
+        assert_eq!(function.execute(&mut Context::new()), 32);
+
+        // The immediate follows the REX prefix and opcode.
+        let mut memory = arena.0.borrow_mut();
+        memory.make_mut().unwrap();
+        memory.patch(2, &[42]);
+        memory.make_exec().unwrap();
+        drop(memory);
+        assert_eq!(function.execute(&mut Context::new()), 42);
+    }
+
+    #[test]
+    #[should_panic(expected = "patch at 1 out of bounds")]
+    fn test_patch_bounds() {
+        let mut memory = JitMemory::new(1).unwrap();
+        memory.append(&[0xc3]).unwrap();
+        memory.patch(1, &[0xc3]);
+    }
+
+    #[test]
+    fn test_shared() {
+        let arena = Arena::new(1).unwrap();
+        let functions = (0..3).map(|n| arena.load(&returns(n)).unwrap()).collect::<Vec<_>>();
+        // Enough to move everything.
+        let big = arena.load(&[vec![0x90; 3 * JitMemory::get_page_size()], returns(7)].concat())
+                       .unwrap();
+        for (n, function) in functions.iter().enumerate() {
+            assert_eq!(function.execute(&mut Context::new()), n as i64);
+        }
+        assert_eq!(big.execute(&mut Context::new()), 7);
+        assert_eq!(arena.0.borrow().len(), 4 * returns(0).len() + 3 * JitMemory::get_page_size());
     }
 }
@@ -0,0 +1,301 @@
+use asm::{Assembler, Cond, Reg};
+use asm::Reg::*;
+use command::Command;
+use optimize::fold;
+use stack;
+use Number;
+
+/// Registers which can hold stack items. They're all callee-saved, so they
+/// survive calls into the context; `frame::enter` saves them for us. `r14` is
+/// the context and `r15` is the top of the stack itself.
+pub const REGISTERS: [Reg; 3] = [Rbx, R12, R13];
+
+/// An item on the stack which hasn't been pushed to the context yet.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Value {
+    Imm(Number),
+    Reg(Reg),
+}
+
+/// Puts a value in a register.
+fn load(a: &mut Assembler, dst: Reg, value: Value) {
+    match value {
+        Value::Reg(src) if src == dst => {}
+        Value::Reg(src) => a.mov(dst, src),
+        Value::Imm(n) => a.mov_const(dst, n as u64),
+    }
+}
+
+/// The register a value is in, loading it into `scratch` if it's a constant.
+fn register(a: &mut Assembler, value: Value, scratch: Reg) -> Reg {
+    match value {
+        Value::Reg(r) => r,
+        Value::Imm(n) => {
+            a.mov_const(scratch, n as u64);
+            scratch
+        }
+    }
+}
+
+/// Keeps the top of the stack in registers within a basic block, so that
+/// straight-line stack code turns into plain instructions rather than calls
+/// into the context. Whatever is held back is pushed to the context before
+/// anything which can see the stack, and at the end of each block.
+pub struct Lowering {
+    enabled: bool,
+    /// Items above the top of the context's stack, top last.
+    stack: Vec<Value>,
+}
+
+impl Lowering {
+    /// Lowering can be turned off, in which case every command is assembled
+    /// on its own.
+    pub fn new(enabled: bool) -> Self {
+        Lowering {
+            enabled,
+            stack: vec![],
+        }
+    }
+
+    /// Pushes everything held back to the context.
+    pub fn flush(&mut self, a: &mut Assembler) {
+        for value in self.stack.drain(..) {
+            load(a, Rsi, value);
+            stack::push(a);
+        }
+    }
+
+    fn free_register(&self) -> Option<Reg> {
+        REGISTERS.iter().cloned().find(|&r| !self.stack.contains(&Value::Reg(r)))
+    }
+
+    /// Makes sure at least `n` items are held back, popping any more we need
+    /// from the context, or returns false if there aren't enough registers.
+    /// Popping an empty stack is handled by the context just as it would be
+    /// without lowering.
+    fn fill(&mut self, n: usize, a: &mut Assembler) -> bool {
+        let needed = n.saturating_sub(self.stack.len());
+        let free = REGISTERS.iter().filter(|&&r| !self.stack.contains(&Value::Reg(r))).count();
+        if needed > free {
+            return false;
+        }
+        for _ in 0..needed {
+            let r = self.free_register().unwrap();
+            stack::pop(a);
+            a.mov(r, Rax);
+            self.stack.insert(0, Value::Reg(r));
+        }
+        true
+    }
+
+    /// Assembles the command on its own, after pushing everything.
+    fn fallback(&mut self, command: Command, a: &mut Assembler) {
+        self.flush(a);
+        command.assemble(a);
+    }
+
+    fn arithmetic(&mut self, command: Command, a: &mut Assembler) {
+        if !self.fill(2, a) {
+            return self.fallback(command, a);
+        }
+        let len = self.stack.len();
+        let (lhs, rhs) = (self.stack[len - 2], self.stack[len - 1]);
+
+        if let (Value::Imm(x), Value::Imm(y)) = (lhs, rhs) {
+            if let Some(n) = fold(x, y, &command) {
+                self.stack.truncate(len - 2);
+                self.stack.push(Value::Imm(n));
+                return;
+            }
+        }
+
+        // Write the result over the first operand if nothing else needs it.
+        let dst = match lhs {
+            Value::Reg(r) if self.stack.iter().filter(|&&v| v == lhs).count() == 1 => r,
+            _ => {
+                match self.free_register() {
+                    Some(r) => r,
+                    None => return self.fallback(command, a),
+                }
+            }
+        };
+        self.stack.truncate(len - 2);
+
+        match command {
+            Command::Divide | Command::Modulus => {
+                load(a, Rax, lhs);
+                let src = register(a, rhs, Rcx);
+                a.cqo();
+                a.idiv(src);
+                a.mov(dst, if command == Command::Divide { Rax } else { Rdx });
+            }
+            _ => {
+                load(a, dst, lhs);
+                let src = register(a, rhs, Rcx);
+                match command {
+                    Command::Add => a.add(dst, src),
+                    Command::Subtract => a.sub(dst, src),
+                    Command::Multiply => a.imul(dst, src),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        self.stack.push(Value::Reg(dst));
+    }
+
+    /// Assembles a command, possibly holding back what it does to the stack.
+    pub fn lower(&mut self, command: Command, a: &mut Assembler) {
+        if !self.enabled {
+            return command.assemble(a);
+        }
+        let len = self.stack.len();
+        match command {
+            Command::Push(n) => self.stack.push(Value::Imm(n)),
+            Command::Duplicate if len >= 1 => {
+                let top = self.stack[len - 1];
+                self.stack.push(top);
+            }
+            Command::Copy(n) if n >= 0 && (n as usize) < len => {
+                let value = self.stack[len - 1 - n as usize];
+                self.stack.push(value);
+            }
+            Command::Pop if len >= 1 => {
+                self.stack.pop();
+            }
+            Command::Slide(n) if n >= 0 && (n as usize) < len => {
+                let top = self.stack.pop().unwrap();
+                self.stack.truncate(len - 1 - n as usize);
+                self.stack.push(top);
+            }
+            Command::Swap => {
+                if self.fill(2, a) {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 2, len - 1);
+                } else {
+                    self.fallback(command, a);
+                }
+            }
+            Command::Add | Command::Subtract | Command::Multiply | Command::Divide |
+            Command::Modulus => self.arithmetic(command, a),
+            Command::JumpZero(ref label) |
+            Command::JumpNegative(ref label) if len >= 1 => {
+                let top = self.stack.pop().unwrap();
+                self.flush(a);
+                let r = register(a, top, Rcx);
+                a.test(r, r);
+                let cond = if let Command::JumpZero(_) = command { Cond::E } else { Cond::S };
+                let target = a.named(label);
+                a.jcc(cond, target);
+            }
+            _ => self.fallback(command, a),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command;
+    use std::collections::HashMap;
+    use {l, run_captured, Outcome};
+
+    #[test]
+    fn test_straight_line_has_no_calls() {
+        let mut a = Assembler::new();
+        let mut lowering = Lowering::new(true);
+        for command in [Command::Push(6), Command::Push(7), Command::Duplicate, Command::Swap] {
+            lowering.lower(command, &mut a);
+            assert_eq!(a.len(), 0);
+        }
+        // Arithmetic on constants is folded as it goes.
+        lowering.lower(Command::Multiply, &mut a);
+        lowering.lower(Command::Subtract, &mut a);
+        assert_eq!(a.len(), 0);
+        assert_eq!(lowering.stack, vec![Value::Imm(6 - 49)]);
+
+        // Pushing the result is the only call.
+        lowering.lower(Command::Exit, &mut a);
+        lowering.lower(Command::Deinitialize, &mut a);
+        let mut expected = Assembler::new();
+        expected.mov_imm(Rsi, (6 - 49) as u64);
+        stack::push(&mut expected);
+        Command::Exit.assemble(&mut expected);
+        Command::Deinitialize.assemble(&mut expected);
+        command::stubs(&mut a);
+        command::stubs(&mut expected);
+        assert_eq!(a.finish(), expected.finish());
+    }
+
+    #[test]
+    fn test_registers() {
+        // Reads a number, then does arithmetic on it in registers.
+        let program = vec![Command::Push(0),
+                           Command::ReadNum,
+                           Command::Retrieve,
+                           Command::Duplicate,
+                           Command::Multiply,
+                           Command::Push(3),
+                           Command::Swap,
+                           Command::Subtract,
+                           Command::Push(2),
+                           Command::Divide,
+                           Command::Duplicate,
+                           Command::Push(5),
+                           Command::Modulus,
+                           Command::Add,
+                           Command::OutputNum,
+                           Command::Exit];
+        let mut heap = HashMap::new();
+        heap.insert(0, 7);
+        // ((3 - 49) / 2) = -23, -23 % 5 = -3, -23 + -3 = -26
+        assert_eq!(run_captured(program, b"7\n"),
+                   Outcome {
+                       stdout: b"-26".to_vec(),
+                       stack: vec![0, -26],
+                       heap,
+                   });
+    }
+
+    #[test]
+    fn test_runs_out_of_registers() {
+        // Keeps eight different values from one read, more than there are
+        // registers for, then adds them up.
+        let mut program = vec![Command::Push(0),
+                               Command::ReadNum,
+                               Command::Retrieve,
+                               Command::Push(0),
+                               Command::Add];
+        for _ in 0..7 {
+            program.extend(vec![Command::Duplicate, Command::Push(1), Command::Add]);
+        }
+        for _ in 0..7 {
+            program.push(Command::Add);
+        }
+        program.extend(vec![Command::OutputNum, Command::Exit]);
+        // 1 + 2 + ... + 8
+        let outcome = run_captured(program, b"1\n");
+        assert_eq!(outcome.stdout, b"36".to_vec());
+        assert_eq!(outcome.stack, vec![0, 36]);
+    }
+
+    #[test]
+    fn test_branches_on_register() {
+        // Counts down from 3, keeping the counter in a register within each
+        // pass through the loop.
+        let program = vec![Command::Push(3),
+                           Command::Mark(l("0")),
+                           Command::Duplicate,
+                           Command::OutputNum,
+                           Command::Pop,
+                           Command::Push(1),
+                           Command::Subtract,
+                           Command::Duplicate,
+                           Command::JumpZero(l("1")),
+                           Command::Jump(l("0")),
+                           Command::Mark(l("1")),
+                           Command::Exit];
+        let outcome = run_captured(program, b"");
+        assert_eq!(outcome.stdout, b"321".to_vec());
+        assert_eq!(outcome.stack, vec![0]);
+    }
+}
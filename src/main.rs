@@ -6,60 +6,449 @@ extern crate nom;
 
 extern crate wsstd;
 
+#[macro_use]
+mod opcodes;
+mod asm;
+mod cache;
+mod cfg;
+mod cli;
+#[macro_use]
+mod command;
+mod decode;
+mod depth;
+mod dialect;
+mod fault;
+mod frame;
+mod gdb;
+mod graph;
+mod heap;
+mod helpers;
 mod jit;
+mod lower;
+mod minify;
+mod obfuscate;
+mod optimize;
 mod parsers;
-mod command;
+mod perf;
+mod stack;
+mod validate;
 
 use nom::IResult;
-
-use command::Command;
-use jit::{JitFunction, JitMemory};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, process};
+
+use asm::Assembler;
+use cache::{Cache, Compiled};
+use cfg::Cfg;
+use cli::{Graph, Options, Subcommand};
+use command::{encode_program, Command, SourceMap};
+use dialect::{Dialect, Features};
+use jit::{Arena, JitFunction, JitMemory};
+use parsers::ParseOptions;
+use validate::{Diagnostic, Severity};
 use wsstd::Context;
 
 pub use wsstd::{Label, Number};
 
-fn get_native_function<'a>(program: Vec<Command>, context: &'a mut Context) -> JitFunction<'a> {
-    let machine_code = program.into_iter()
-                              .map(|x| x.assemble(context))
-                              .collect::<Vec<Vec<u8>>>()
-                              .concat();
+/// Compiles a program to machine code. The context gets the addresses of the
+/// program's labels and the names used for tracing, but the code can be run
+/// against any context, from anywhere in memory.
+///
+/// Hardening hides the program's constants with a new random key each time,
+/// for running programs which aren't trusted.
+fn link(program: Vec<Command>, context: &mut Context, harden: bool) -> (Vec<u8>, SourceMap) {
+    let mut a = Assembler::new();
+    if harden {
+        a.blind(random_key());
+    }
+    link_with(program, context, a)
+}
 
-    // TODO something with labels
+/// Compiles a program with an assembler which has been set up already.
+fn link_with(mut program: Vec<Command>, context: &mut Context, a: Assembler) -> (Vec<u8>, SourceMap) {
+    program.insert(0, Command::Initialize);
+    program.push(Command::Deinitialize);
+
+    command::link_with(program, context, a)
+        .unwrap_or_else(|label| panic!("Undefined label {:?}!", label))
+}
+
+/// A key to hide constants with, from the kernel's random source.
+fn random_key() -> u64 {
+    let mut key = [0u8; 8];
+    let mut filled = 0;
+    while filled < key.len() {
+        let rest = &mut key[filled..];
+        let n = unsafe { libc::getrandom(rest.as_mut_ptr() as *mut libc::c_void, rest.len(), 0) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                panic!("couldn't get a random key: {}", e);
+            }
+            continue;
+        }
+        filled += n as usize;
+    }
+    u64::from_ne_bytes(key)
+}
 
+/// Copies machine code somewhere it can be run.
+fn load(machine_code: &[u8]) -> io::Result<JitFunction> {
     let pages = (machine_code.len() / JitMemory::get_page_size()) + 1;
-    let mut memory = JitMemory::new(pages);
+    Arena::new(pages)?.load(machine_code)
+}
 
-    memory.copy_from(&machine_code[..]);
-    memory.into()
+/// Compiles a program, optionally showing the machine code on stderr.
+fn compile(program: Vec<Command>,
+           context: &mut Context,
+           dump_asm: bool,
+           harden: bool)
+           -> io::Result<(JitFunction, SourceMap)> {
+    let (machine_code, map) = link(program, context, harden);
+    if dump_asm {
+        eprint!("{}", decode::dump(&machine_code, &map, context));
+    }
+    Ok((load(&machine_code)?, map))
 }
 
-fn parse(program: &[u8]) -> Option<Vec<Command>> {
-    match parsers::program(program) {
-        IResult::Done(_, mut program) => {
-            program.insert(0, Command::Initialize);
-            program.push(Command::Deinitialize);
-            Some(program)
+/// Compiles a program, unless it's in the cache from an earlier run, in which
+/// case the context is set up just as compiling it would have. Hardened code
+/// is never cached, since its key would be reused. Code from the cache comes
+/// without a map of which command it came from, so it isn't used when the
+/// map is wanted to name the command a fault came from, or to name the code
+/// for `perf` or GDB.
+fn compile_cached(options: &Options,
+                  source: &[u8],
+                  program: Vec<Command>,
+                  context: &mut Context)
+                  -> io::Result<(JitFunction, Option<SourceMap>)> {
+    let wants_map = options.catch_faults || options.perf_map || options.gdb;
+    let cache = match Cache::open() {
+        Some(cache) if options.cache && !options.dump_asm && !options.harden && !wants_map => cache,
+        _ => {
+            let (function, map) = compile(program, context, options.dump_asm, options.harden)?;
+            return Ok((function, Some(map)));
         }
+    };
+    let key = cache::key(source, options);
+    if let Some(compiled) = cache.load(key) {
+        compiled.install(context);
+        return Ok((load(&compiled.code)?, None));
+    }
+
+    let (machine_code, map) = link(program, context, false);
+    let compiled = Compiled::new(machine_code, context);
+    if let Err(e) = cache.store(key, &compiled) {
+        eprintln!("couldn't cache {}: {}", options.input, e);
+    }
+    Ok((load(&compiled.code)?, Some(map)))
+}
+
+#[cfg(test)]
+fn get_native_function(program: Vec<Command>, context: &mut Context) -> JitFunction {
+    compile(program, context, false, false).expect("couldn't map memory for the program").0
+}
+
+/// Parses a program, ignoring comments. Fails unless the whole program is
+/// made of valid commands.
+fn parse_with(program: &[u8], options: &ParseOptions) -> Option<Vec<Command>> {
+    match parsers::program_with(&parsers::strip_comments(program), options) {
+        IResult::Done(&[], program) => Some(program),
         _ => None,
     }
 }
 
-fn main() {
+/// Parses a program with the default options.
+#[cfg(test)]
+fn parse(program: &[u8]) -> Option<Vec<Command>> {
+    parse_with(program, &ParseOptions::default())
+}
 
-    let input_file = unimplemented!();
-    let input = unimplemented!();
+/// Everything a program can be observed to do, for comparing two runs.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Outcome {
+    stdout: Vec<u8>,
+    stack: Vec<Number>,
+    heap: HashMap<Number, Number>,
+}
 
-    let program = parse(input).expect("Invalid program!");
+/// Runs a program with the given stdin, capturing everything it does.
+#[cfg(test)]
+fn run_captured(program: Vec<Command>, stdin: &[u8]) -> Outcome {
+    run_limited(program, stdin, None).0.expect("ran out of steps without a limit")
+}
+
+/// Runs a program with the given stdin, capturing everything it does, unless
+/// it takes more than `steps` steps. Also says how many steps it took, if
+/// they were counted.
+fn run_limited(program: Vec<Command>, stdin: &[u8], steps: Option<u64>) -> (Option<Outcome>, u64) {
+    let mut context = Context::new();
+    let stdout = Rc::new(RefCell::new(Vec::new()));
+    context.capture_stdout(stdout.clone());
+    context.provide_stdin(stdin);
+
+    let mut a = Assembler::new();
+    if let Some(steps) = steps {
+        a.limit_steps();
+        context.steps_left = steps as i64;
+    }
+    let (machine_code, _) = link_with(program, &mut context, a);
+    load(&machine_code).expect("couldn't map memory for the program").execute(&mut context);
+
+    let taken = steps.map_or(0, |steps| (steps as i64 - context.steps_left) as u64);
+    if context.steps_left < 0 {
+        return (None, taken);
+    }
+    let stdout = stdout.borrow().clone();
+    let outcome = Outcome {
+        stdout,
+        stack: context.stack,
+        heap: context.heap.to_map(),
+    };
+    (Some(outcome), taken)
+}
+
+/// A label from a string of 0s and 1s, for writing programs in tests.
+#[cfg(test)]
+fn l(bits: &str) -> Label {
+    Label::Name(bits.chars().map(|c| c == '1').collect())
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Prints any problems with the program, returning whether it's safe to run.
+fn check(options: &Options, program: &[Command]) -> bool {
+    let diagnostics = validate::validate(program, options.parse.dialect);
+    report(options, program, &diagnostics);
+    diagnostics.iter().all(|d| d.severity < Severity::Error)
+}
+
+/// Prints any problems with a program which is going to be run anyway. They
+/// only get in the way if it actually goes wrong, so they're all warnings.
+fn warn(options: &Options, program: &[Command]) {
+    let diagnostics = validate::validate(program, options.parse.dialect)
+                          .into_iter()
+                          .map(|d| Diagnostic { severity: Severity::Warning, ..d })
+                          .collect::<Vec<_>>();
+    report(options, program, &diagnostics);
+}
+
+fn report(options: &Options, program: &[Command], diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        let command = program.get(diagnostic.index)
+                             .map(|c| format!(" ({})", c))
+                             .unwrap_or_default();
+        eprintln!("{}: {}{}", options.input, diagnostic, command);
+    }
+}
+
+fn run(options: &Options, source: &[u8], program: Vec<Command>) {
+    let optimized = optimize::optimize(program.clone(), options.opt_level);
+    if options.dump_ir {
+        eprint!("; before optimizing\n{}; after optimizing\n{}",
+                command::disassemble(&program),
+                command::disassemble(&optimized));
+    }
+    let program = optimized;
 
     let mut context = Context::new();
     {
-        let program = get_native_function(program, &mut context);
+        let (program, map) = compile_cached(options, source, program, &mut context)
+                                 .unwrap_or_else(|e| {
+                                     eprintln!("couldn't load the compiled program: {}", e);
+                                     process::exit(1);
+                                 });
+        let symbols = map.as_ref().map(|map| perf::symbols(&options.input, program.len(), map));
+        if let (true, Some(symbols)) = (options.perf_map, symbols.as_ref()) {
+            let entries = perf::entries(&options.input, program.len(), symbols);
+            if let Err(e) = perf::write(&perf::path(), program.address(), &entries) {
+                eprintln!("couldn't write {}: {}", perf::path().display(), e);
+            }
+        }
+        // Dropped before the program is.
+        let _registration = match (map.as_ref(), symbols.as_ref()) {
+            (Some(map), Some(symbols)) if options.gdb => {
+                Some(gdb::register(program.address(), program.len(), map, symbols))
+            }
+            _ => None,
+        };
 
-        program.execute();
+        if !options.catch_faults {
+            program.execute(&mut context);
+        } else if let Err(fault) = program.execute_guarded(&mut context) {
+            match map.as_ref().and_then(|map| fault.command(map)) {
+                Some(command) => eprintln!("{}: {} in {}", options.input, fault, command),
+                None => eprintln!("{}: {}", options.input, fault),
+            }
+            process::exit(1);
+        }
     }
     println!("Done!\n{:?}", context);
 }
 
+fn minify(options: &Options, source: &[u8], program: Vec<Command>) -> io::Result<()> {
+    let minified = encode_program(&minify::minify(program));
+    write_output(options, &minified)?;
+
+    let saved = source.len().saturating_sub(minified.len());
+    eprintln!("{} -> {} bytes, saved {} bytes ({:.1}%)",
+              source.len(),
+              minified.len(),
+              saved,
+              100.0 * saved as f64 / source.len().max(1) as f64);
+    Ok(())
+}
+
+fn write_output(options: &Options, output: &[u8]) -> io::Result<()> {
+    match options.output {
+        Some(ref path) => File::create(path)?.write_all(output),
+        None => io::stdout().write_all(output),
+    }
+}
+
+/// How long a program can run while checking that obfuscating it didn't
+/// change what it does.
+const OBFUSCATE_STEPS: u64 = 10_000_000;
+
+fn obfuscate(options: &Options, program: Vec<Command>) -> Result<(), String> {
+    let stdin = match options.stdin {
+        Some(ref path) => {
+            read_file(path).map_err(|e| format!("couldn't read {}: {}", path, e))?
+        }
+        // Reading from nothing only checks what happens at the end of input.
+        None if program.iter().any(|c| *c == Command::ReadChar || *c == Command::ReadNum) => {
+            return Err(format!("{} reads input, so it needs --stdin to be checked",
+                               options.input));
+        }
+        None => vec![],
+    };
+    let seed = options.seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    });
+
+    let obfuscated = obfuscate::obfuscate(program.clone(), seed);
+
+    // Make sure we didn't break anything before handing it over. Each block
+    // of the original takes at most three steps once it's obfuscated: falling
+    // through a conditional jump to it, its mark, and an opaque predicate. The obfuscated program's
+    // first block counts where the original's doesn't.
+    let (expected, steps) = match run_limited(program, &stdin, Some(OBFUSCATE_STEPS)) {
+        (Some(expected), steps) => (expected, steps),
+        (None, _) => {
+            return Err(format!("{} didn't finish within {} steps, so it can't be checked",
+                               options.input,
+                               OBFUSCATE_STEPS))
+        }
+    };
+    match run_limited(obfuscated.clone(), &stdin, Some(3 * (steps + 1))).0 {
+        Some(ref actual) if *actual == expected => {}
+        Some(actual) => {
+            return Err(format!("obfuscated program behaves differently (seed {}):\n\
+                                expected {:?}\n\
+                                got {:?}",
+                               seed,
+                               expected,
+                               actual))
+        }
+        None => return Err(format!("obfuscated program doesn't finish (seed {})", seed)),
+    }
+
+    write_output(options, &encode_program(&obfuscated))
+        .map_err(|e| format!("couldn't write output: {}", e))?;
+    eprintln!("obfuscated with seed {}", seed);
+    Ok(())
+}
+
+/// Reports which instructions newer than 0.2 or extensions the program uses,
+/// and whether it fits the requested dialect.
+fn features(options: &Options, program: &[Command]) {
+    let features = Features::of(program);
+    print!("{}", features);
+
+    if features.dialect > options.parse.dialect {
+        eprintln!("{} is not compatible with Whitespace {}",
+                  options.input,
+                  options.parse.dialect);
+        process::exit(1);
+    }
+}
+
+/// Writes the control-flow or call graph of the program as DOT.
+fn graph(options: &Options, program: &[Command]) -> io::Result<()> {
+    let cfg = Cfg::new(program);
+    let dot = match options.graph {
+        Graph::Cfg => graph::cfg_dot(&cfg),
+        Graph::CallGraph => graph::call_graph_dot(&cfg),
+    };
+    write_output(options, dot.as_bytes())
+}
+
+fn main() {
+    let options = Options::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, cli::USAGE);
+        process::exit(2);
+    });
+
+    let input = read_file(&options.input).unwrap_or_else(|e| {
+        eprintln!("couldn't read {}: {}", options.input, e);
+        process::exit(1);
+    });
+
+    // These explain what's wrong with instructions which are too new, so
+    // let them through the parser.
+    let parse_options = match options.subcommand {
+        Subcommand::Check | Subcommand::Features => {
+            ParseOptions {
+                dialect: Dialect::default(),
+                ..options.parse.clone()
+            }
+        }
+        _ => options.parse.clone(),
+    };
+    let program = parse_with(&input, &parse_options).expect("Invalid program!");
+
+    match options.subcommand {
+        Subcommand::Run => {
+            warn(&options, &program);
+            run(&options, &input, program)
+        }
+        Subcommand::Check => {
+            if !check(&options, &program) {
+                process::exit(1);
+            }
+        }
+        Subcommand::Disassemble => print!("{}", command::disassemble(&program)),
+        Subcommand::Features => features(&options, &program),
+        Subcommand::Graph => {
+            graph(&options, &program).unwrap_or_else(|e| {
+                eprintln!("couldn't write output: {}", e);
+                process::exit(1);
+            })
+        }
+        Subcommand::Minify => {
+            minify(&options, &input, program).unwrap_or_else(|e| {
+                eprintln!("couldn't write output: {}", e);
+                process::exit(1);
+            })
+        }
+        Subcommand::Obfuscate => {
+            obfuscate(&options, program).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            })
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
@@ -92,18 +481,15 @@ mod tests {
 
     macro_rules! out {
         ( [ $( $stack:expr),* ]; $stdout:expr; { $( $key:expr => $value:expr )* } ) => {{
-            let mut stack = Vec::new();
-            $(
-                stack.push($stack);
-            )*
+            let stack = vec![$($stack),*];
             let mut heap = HashMap::new();
             $(
                 heap.insert($key, $value);
             )*
             $crate::tests::Output {
                 stdout: $stdout.to_string(),
-                stack: stack,
-                heap: heap,
+                stack,
+                heap,
             }
         }};
     }
@@ -114,7 +500,6 @@ mod tests {
         })*  ) => {
 
             $(mod $pkg {
-                use parsers::program;
                 use wsstd::Context;
                 use std::rc::Rc;
                 use std::cell::RefCell;
@@ -140,7 +525,7 @@ mod tests {
 
                         {
                             let program = get_native_function(program, &mut context);
-                            program.execute();
+                            program.execute(&mut context);
                         }
 
                         // we reverse the stack here so that the top of the stack
@@ -159,6 +544,66 @@ mod tests {
         };
     }
 
+    #[test]
+    #[cfg(feature = "extensions")]
+    fn extensions() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use wsstd::Context;
+
+        // push 1, dump_stack, trace_on, push 2, trace_off, dump_heap
+        let program = ::parse(b"   \t\n\n\n  \n\n\t    \t \n\n\n\t\t\n\n \t").unwrap();
+        let mut context = Context::new();
+        let debug = Rc::new(RefCell::new(Vec::new()));
+        context.capture_debug(debug.clone());
+        {
+            let program = ::get_native_function(program, &mut context);
+            program.execute(&mut context);
+        }
+
+        assert_eq!(String::from_utf8(debug.borrow().clone()).unwrap(),
+                   "stack: [1]\n\
+                    trace: push 2                   [1]\n\
+                    trace: traceoff                 [1, 2]\n\
+                    heap: []\n");
+    }
+
+    #[test]
+    fn reuse() {
+        use command::Command;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use wsstd::Context;
+
+        // Reads a number into the heap, then prints it.
+        let program = vec![Command::Push(0),
+                           Command::ReadNum,
+                           Command::Retrieve,
+                           Command::OutputNum];
+        let (function, _) = ::compile(program, &mut Context::new(), false, false).unwrap();
+
+        let run = |context: &mut Context, stdin: &str| {
+            let stdout = Rc::new(RefCell::new(Vec::new()));
+            context.capture_stdout(stdout.clone());
+            context.provide_stdin(stdin);
+            function.execute(context);
+            let stdout = stdout.borrow().clone();
+            String::from_utf8(stdout).unwrap()
+        };
+
+        let mut first = Context::new();
+        let mut second = Context::new();
+        assert_eq!(run(&mut first, "5\n"), "5");
+        assert_eq!(run(&mut second, "7\n"), "7");
+        assert_eq!(first.stack, vec![0, 5]);
+        assert_eq!(second.stack, vec![0, 7]);
+
+        // Running again carries on from where the context was left.
+        assert_eq!(run(&mut first, "9\n"), "9");
+        assert_eq!(first.stack, vec![0, 5, 0, 9]);
+        assert_eq!(first.heap, [(0, 9)].iter().cloned().collect::<HashMap<_, _>>());
+    }
+
     gen_tests! {
         stack: {
             // push 1
@@ -199,6 +644,10 @@ mod tests {
             char_in:   inp!("   \t \t\n\t\n\t "; "A\n")    => out!([5]; ""; { 5 => 65 });
             // push "101", in_int
             int_in:    inp!("   \t \t\n\t\n\t\t"; "65")    => out!([5]; ""; { 5 => 65 });
+            // push "101", in_char, with nothing to read
+            char_eof:  inp!("   \t \t\n\t\n\t "; "")      => out!([5]; ""; { 5 => 0 });
+            // push "101", in_int, with no number to read
+            int_eof:   inp!("   \t \t\n\t\n\t\t"; "x")     => out!([5]; ""; { 5 => 0 });
         }
 
         arithmetic: {
@@ -233,6 +682,34 @@ mod tests {
         flow: {
             // push 1, exit, push 2
             exit:      inp!("   \t\n\n\n\n   \t \n")       => out!([1]; "";  {});
+            // push 1, jump "1", push 2, mark "1"
+            jump:      inp!("   \t\n\n \n\t\n   \t \n\n  \t\n")
+                                                           => out!([1]; ""; {});
+            // push 0, jump_zero "1", push 2, mark "1", push 3
+            jump_zero: inp!("   \n\n\t \t\n   \t \n\n  \t\n   \t\t\n")
+                                                           => out!([3]; ""; {});
+            // push 1, jump_zero "1", push 2, mark "1"
+            no_jump_zero: inp!("   \t\n\n\t \t\n   \t \n\n  \t\n")
+                                                           => out!([2]; ""; {});
+            // push -1, jump_negative "1", push 2, mark "1"
+            jump_neg:  inp!("  \t\t\n\n\t\t\t\n   \t \n\n  \t\n")
+                                                           => out!([]; ""; {});
+            // call "1", push 3, exit, mark "1", push 1, return
+            call:      inp!("\n \t\t\n   \t\t\n\n\n\n\n  \t\n   \t\n\n\t\n")
+                                                           => out!([3, 1]; ""; {});
+            // call "1", push 2, mark "1", push 1, exit
+            exit_call: inp!("\n \t\t\n   \t \n\n  \t\n   \t\n\n\n\n")
+                                                           => out!([1]; ""; {});
+            // push 3, mark "1", out_int, push 1, subtract, duplicate,
+            // jump_zero " ", jump "1", mark " "
+            count:     inp!("   \t\t\n\n  \t\n\t\n \t   \t\n\t  \t \n \n\t  \n\n \n\t\n\n   \n")
+                                                           => out!([0]; "321"; {});
+        }
+
+        stack_slide: {
+            // push 1, push 2, push 3, slide 2
+            slide:     inp!("   \t\n   \t \n   \t\t\n \t\n \t \n")
+                                                           => out!([3]; ""; {});
         }
     }
 
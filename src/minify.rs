@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use command::Command;
+use Label;
+
+/// Returns the `n`th shortest label, counting 0, 1, 00, 01, 10, 11, 000, ...
+fn nth_label(n: usize) -> Vec<bool> {
+    // There are 2^k labels of length k, so labels of length k start at
+    // index 2^k - 2. Adding 2 makes the length the position of the top bit.
+    let index = n + 2;
+    let width = 63 - (index as u64).leading_zeros() as usize;
+    (0..width).rev().map(|bit| index & (1 << bit) != 0).collect()
+}
+
+/// Renames every label so that the most used labels get the shortest names.
+/// Numbers are re-encoded without leading zeros and comments are dropped when
+/// the result is encoded, so this only has to deal with labels.
+pub fn minify(mut program: Vec<Command>) -> Vec<Command> {
+    // Count uses, remembering the first occurrence to break ties
+    // deterministically.
+    let mut uses: HashMap<Label, (usize, usize)> = HashMap::new();
+    for (i, label) in program.iter().filter_map(Command::label).enumerate() {
+        uses.entry(label.clone()).or_insert((0, i)).0 += 1;
+    }
+
+    let mut by_use = uses.into_iter().collect::<Vec<_>>();
+    by_use.sort_by_key(|&(_, (count, first))| (!count, first));
+
+    let names = by_use.into_iter()
+                      .enumerate()
+                      .map(|(i, (label, _))| (label, Label::Name(nth_label(i))))
+                      .collect::<HashMap<Label, Label>>();
+
+    for label in program.iter_mut().filter_map(Command::label_mut) {
+        *label = names[label].clone();
+    }
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::encode_program;
+    use std::collections::HashSet;
+    use {l, parse, run_captured};
+
+    #[test]
+    fn test_nth_label() {
+        let labels = (0..7).map(nth_label).collect::<Vec<_>>();
+        assert_eq!(labels,
+                   vec![vec![false],
+                        vec![true],
+                        vec![false, false],
+                        vec![false, true],
+                        vec![true, false],
+                        vec![true, true],
+                        vec![false, false, false]]);
+    }
+
+    #[test]
+    fn test_most_used_label_is_shortest() {
+        let program = vec![Command::Mark(l("0000")),
+                           Command::Mark(l("11111111")),
+                           Command::Jump(l("11111111")),
+                           Command::JumpZero(l("11111111")),
+                           Command::Call(l("0000"))];
+        assert_eq!(minify(program),
+                   vec![Command::Mark(l("1")),
+                        Command::Mark(l("0")),
+                        Command::Jump(l("0")),
+                        Command::JumpZero(l("0")),
+                        Command::Call(l("1"))]);
+    }
+
+    #[test]
+    fn test_minified_source_is_smaller() {
+        // push "001" with redundant zeros, then a long label, with comments
+        let source = b"push      \t\nmark\n  \t\t\t\t\t\t\t\njump\n \n\t\t\t\t\t\t\t\n";
+        let program = parse(source).unwrap();
+        let minified = encode_program(&minify(program.clone()));
+
+        assert!(minified.len() < source.len());
+        assert_eq!(minified, b"   \t\n\n   \n\n \n \n".to_vec());
+        assert_eq!(parse(&minified).unwrap().len(), program.len());
+    }
+
+    /// Counts down from 3, printing each number from a subroutine, with long
+    /// labels and padded numbers to minify.
+    fn countdown() -> Vec<Command> {
+        vec![Command::Push(3),
+             Command::Mark(l("0101010101")),
+             Command::Call(l("111000111")),
+             Command::Push(1),
+             Command::Subtract,
+             Command::Duplicate,
+             Command::JumpZero(l("000000")),
+             Command::Jump(l("0101010101")),
+             Command::Mark(l("000000")),
+             Command::Push(-10),
+             Command::Push(0),
+             Command::Store,
+             Command::Exit,
+             Command::Mark(l("111000111")),
+             Command::Duplicate,
+             Command::OutputNum,
+             Command::Return]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = countdown();
+        let minified = minify(program.clone());
+        assert_eq!(parse(&encode_program(&minified)).unwrap(), minified);
+
+        // The same commands, with each label renamed to a different one.
+        let mut renamed = HashMap::new();
+        for (before, after) in program.iter().zip(&minified) {
+            let mut expected = before.clone();
+            if let (Some(label), Some(new)) = (expected.label_mut(), after.label()) {
+                assert_eq!(renamed.entry(label.clone()).or_insert_with(|| new.clone()), new);
+                *label = new.clone();
+            }
+            assert_eq!(&expected, after);
+        }
+        assert_eq!(program.len(), minified.len());
+        assert_eq!(renamed.values().collect::<HashSet<_>>().len(), renamed.len());
+    }
+
+    #[test]
+    fn test_differential() {
+        let program = countdown();
+        let minified = parse(&encode_program(&minify(program.clone()))).unwrap();
+        assert_eq!(run_captured(minified, b""), run_captured(program, b""));
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+
+use cfg::Cfg;
+use command::Command;
+use {Label, Number};
+
+/// A small xorshift generator. We don't need good randomness, just something
+/// that's reproducible from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, one_in: u64) -> bool {
+        self.below(one_in) == 0
+    }
+
+    /// A small number which can be squared or doubled without overflowing.
+    fn small(&mut self) -> Number {
+        self.below(1 << 20) as Number - (1 << 19)
+    }
+}
+
+struct Obfuscator {
+    rng: Rng,
+    used: HashSet<Label>,
+}
+
+impl Obfuscator {
+    /// Makes up a random label which hasn't been used yet.
+    fn fresh_label(&mut self) -> Label {
+        loop {
+            let len = 8 + self.rng.below(17);
+            let label = Label::Name((0..len).map(|_| self.rng.chance(2)).collect());
+            if self.used.insert(label.clone()) {
+                return label;
+            }
+        }
+    }
+
+    /// Gives every label in the program a new random name.
+    fn scramble_labels(&mut self, program: &mut [Command]) {
+        let mut names = HashMap::new();
+        for label in program.iter_mut().filter_map(Command::label_mut) {
+            if !names.contains_key(label) {
+                let name = self.fresh_label();
+                names.insert(label.clone(), name);
+            }
+            *label = names[label].clone();
+        }
+    }
+
+    /// Replaces a push with an equivalent sequence of arithmetic.
+    fn split_constant(&mut self, n: Number) -> Vec<Command> {
+        let a = self.rng.small();
+        match self.rng.below(4) {
+            0 => vec![Command::Push(a), Command::Push(n.wrapping_sub(a)), Command::Add],
+            1 => vec![Command::Push(n.wrapping_add(a)), Command::Push(a), Command::Subtract],
+            2 if n % 2 == 0 => vec![Command::Push(n / 2), Command::Push(2), Command::Multiply],
+            _ => vec![Command::Push(n)],
+        }
+    }
+
+    /// A predicate which is never true, followed by a jump to `dead` if it is.
+    fn opaque_predicate(&mut self, dead: Label) -> Vec<Command> {
+        let a = self.rng.small();
+        if self.rng.chance(2) {
+            // a * a is never negative
+            vec![Command::Push(a),
+                 Command::Duplicate,
+                 Command::Multiply,
+                 Command::JumpNegative(dead)]
+        } else {
+            // an odd number is never even
+            vec![Command::Push(a.abs() * 2 + 1),
+                 Command::Push(2),
+                 Command::Modulus,
+                 Command::JumpZero(dead)]
+        }
+    }
+
+    /// Some plausible looking code which is never run, ending in a jump to
+    /// `target`.
+    fn dead_code(&mut self, label: Label, target: Label) -> Vec<Command> {
+        let mut block = vec![Command::Mark(label)];
+        for _ in 0..2 + self.rng.below(5) {
+            block.push(match self.rng.below(8) {
+                0 => Command::Duplicate,
+                1 => Command::Swap,
+                2 => Command::Add,
+                3 => Command::Subtract,
+                4 => Command::Multiply,
+                5 => Command::Store,
+                6 => Command::Retrieve,
+                _ => Command::Push(self.rng.small()),
+            });
+        }
+        block.push(Command::Jump(target));
+        block
+    }
+
+    /// Splits a program into blocks which each start with a mark and end in
+    /// an unconditional transfer of control, so they can be placed in any
+    /// order. The last block is an empty block marking the end of the program.
+    fn split_blocks(&mut self, program: &[Command]) -> Vec<Vec<Command>> {
+        let mut blocks = {
+            let cfg = Cfg::new(program);
+            (0..cfg.blocks.len()).map(|id| cfg.commands(id).to_vec()).collect::<Vec<_>>()
+        };
+        blocks.push(vec![]);
+
+        for block in &mut blocks {
+            match block.first() {
+                Some(&Command::Mark(_)) => {}
+                _ => block.insert(0, Command::Mark(self.fresh_label())),
+            }
+        }
+
+        for i in 0..blocks.len() - 1 {
+            let falls_through = !matches!(blocks[i].last(),
+                                          Some(&Command::Jump(_)) |
+                                          Some(&Command::Return) |
+                                          Some(&Command::Exit));
+            if falls_through {
+                let next = blocks[i + 1][0].label().unwrap().clone();
+                blocks[i].push(Command::Jump(next));
+            }
+        }
+        blocks
+    }
+
+    fn obfuscate(&mut self, mut program: Vec<Command>) -> Vec<Command> {
+        self.scramble_labels(&mut program);
+
+        let program = program.into_iter()
+                             .flat_map(|command| match command {
+                                 Command::Push(n) => self.split_constant(n),
+                                 _ => vec![command],
+                             })
+                             .collect::<Vec<Command>>();
+
+        let mut blocks = self.split_blocks(&program);
+        let end = blocks.pop().unwrap();
+
+        // Hide some dead code behind opaque predicates.
+        let mut dead_blocks = vec![];
+        for i in 0..blocks.len() {
+            if self.rng.chance(2) {
+                let dead = self.fresh_label();
+                let target = blocks[self.rng.below(blocks.len() as u64) as usize][0]
+                                 .label()
+                                 .unwrap()
+                                 .clone();
+                dead_blocks.push(self.dead_code(dead.clone(), target));
+                let predicate = self.opaque_predicate(dead);
+                blocks[i].splice(1..1, predicate);
+            }
+        }
+
+        // The first block is the entry point and the end has to stay at the
+        // end, but everything else can go anywhere.
+        let mut rest = blocks.split_off(1);
+        rest.extend(dead_blocks);
+        while !rest.is_empty() {
+            let i = self.rng.below(rest.len() as u64) as usize;
+            blocks.push(rest.swap_remove(i));
+        }
+        blocks.push(end);
+
+        blocks.concat()
+    }
+}
+
+/// Rewrites a program into an equivalent but much harder to read one. The
+/// same seed always gives the same result.
+pub fn obfuscate(program: Vec<Command>, seed: u64) -> Vec<Command> {
+    let mut used = HashSet::new();
+    for label in program.iter().filter_map(Command::label) {
+        used.insert(label.clone());
+    }
+
+    Obfuscator {
+        rng: Rng::new(seed),
+        used,
+    }
+    .obfuscate(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {parse, run_captured, run_limited};
+
+    // push 3, mark "1", out_int, push 1, subtract, duplicate,
+    // jump_zero " ", jump "1", mark " "
+    const COUNT: &[u8] = b"   \t\t\n\n  \t\n\t\n \t   \t\n\t  \t \n \n\t  \n\n \n\t\n\n   \n";
+
+    // push 5, call "1", out_char, exit,
+    // mark "1", push 60, add, return
+    const CALL: &[u8] = b"   \t \t\n\n \t\t\n\t\n  \n\n\n\n  \t\n   \t\t\t\t  \n\t   \n\t\n";
+
+    #[test]
+    fn test_same_seed_same_output() {
+        let program = parse(COUNT).unwrap();
+        assert_eq!(obfuscate(program.clone(), 7), obfuscate(program.clone(), 7));
+        assert!(obfuscate(program.clone(), 7) != obfuscate(program, 8));
+    }
+
+    #[test]
+    fn test_labels_are_scrambled() {
+        let program = parse(COUNT).unwrap();
+        let original = program.iter().filter_map(Command::label).cloned().collect::<HashSet<_>>();
+        for command in obfuscate(program, 1) {
+            if let Some(label) = command.label() {
+                assert!(!original.contains(label));
+            }
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        for source in &[COUNT, CALL] {
+            let program = parse(source).unwrap();
+            let (expected, steps) = run_limited(program.clone(), b"", Some(1000));
+            assert_eq!(expected, Some(run_captured(program.clone(), b"")));
+            for seed in 0..20 {
+                let obfuscated = obfuscate(program.clone(), seed);
+                assert!(obfuscated.len() > program.len());
+                // within the limit the command line gives it
+                assert_eq!(run_limited(obfuscated, b"", Some(3 * (steps + 1))).0, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // mark "1", push 1, out_int, jump "1"
+        let forever = parse(b"\n  \t\n   \t\n\t\n \t\n \n\t\n").unwrap();
+        assert_eq!(run_limited(forever.clone(), b"", Some(100)), (None, 101));
+        assert_eq!(run_limited(obfuscate(forever, 3), b"", Some(100)).0, None);
+
+        let (outcome, steps) = run_limited(parse(COUNT).unwrap(), b"", Some(100));
+        assert_eq!(outcome.unwrap().stdout, b"321");
+        // the mark three times, and falling through the jump_zero three times
+        assert_eq!(steps, 6);
+    }
+}
@@ -0,0 +1,237 @@
+use command::{Command, IMP};
+use dialect::Dialect;
+use {Label, Number};
+
+/// The kind of argument an instruction takes.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ArgKind {
+    None,
+    Number,
+    Label,
+}
+
+/// The argument of a particular instruction.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Operand {
+    None,
+    Number(Number),
+    Label(Label),
+}
+
+/// A number of items on the stack, which may depend on the argument.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Count {
+    Fixed(usize),
+    PlusArg(usize),
+}
+
+impl Count {
+    /// How many items this is for the given argument. Negative arguments
+    /// count as zero.
+    pub fn resolve(self, arg: Number) -> usize {
+        match self {
+            Count::Fixed(n) => n,
+            Count::PlusArg(n) => n + arg.max(0) as usize,
+        }
+    }
+}
+
+/// How an instruction changes the stack: it needs `consumes` items to be
+/// present, and replaces them with `produces` items.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct StackEffect {
+    pub consumes: Count,
+    pub produces: Count,
+}
+
+/// Where an instruction comes from.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Origin {
+    /// Part of the language itself, since the given version.
+    Standard(Dialect),
+    /// A debugging aid which other implementations won't understand.
+    Extension,
+}
+
+/// Everything we know about an instruction, apart from how to compile it.
+pub struct Opcode {
+    pub origin: Origin,
+    pub imp: IMP,
+    /// The characters of the instruction, not including the IMP.
+    pub code: &'static [u8],
+    pub mnemonic: &'static str,
+    pub arg: ArgKind,
+    pub effect: StackEffect,
+    /// Builds the command from its argument.
+    pub build: fn(Operand) -> Command,
+}
+
+impl Opcode {
+    /// The characters of the instruction, including the IMP.
+    pub fn encoding(&self) -> Vec<u8> {
+        [self.imp.code(), self.code].concat()
+    }
+}
+
+/// Types which can be the argument of a command.
+pub trait Argument: Sized {
+    const KIND: ArgKind;
+
+    fn from_operand(operand: Operand) -> Self;
+    fn into_operand(self) -> Operand;
+
+    fn as_label(&self) -> Option<&Label> {
+        None
+    }
+
+    fn as_label_mut(&mut self) -> Option<&mut Label> {
+        None
+    }
+}
+
+impl Argument for Number {
+    const KIND: ArgKind = ArgKind::Number;
+
+    fn from_operand(operand: Operand) -> Self {
+        match operand {
+            Operand::Number(n) => n,
+            _ => panic!("expected a number, got {:?}", operand),
+        }
+    }
+
+    fn into_operand(self) -> Operand {
+        Operand::Number(self)
+    }
+}
+
+impl Argument for Label {
+    const KIND: ArgKind = ArgKind::Label;
+
+    fn from_operand(operand: Operand) -> Self {
+        match operand {
+            Operand::Label(l) => l,
+            _ => panic!("expected a label, got {:?}", operand),
+        }
+    }
+
+    fn into_operand(self) -> Operand {
+        Operand::Label(self)
+    }
+
+    fn as_label(&self) -> Option<&Label> {
+        Some(self)
+    }
+
+    fn as_label_mut(&mut self) -> Option<&mut Label> {
+        Some(self)
+    }
+}
+
+macro_rules! arg_kind {
+    () => { $crate::opcodes::ArgKind::None };
+    ($ty:ident) => { <$ty as $crate::opcodes::Argument>::KIND };
+}
+
+macro_rules! operand_of {
+    () => { $crate::opcodes::Operand::None };
+    ($field:ident) => { $crate::opcodes::Argument::into_operand($field.clone()) };
+}
+
+macro_rules! label_of {
+    () => { None };
+    ($field:ident) => { $crate::opcodes::Argument::as_label($field) };
+}
+
+macro_rules! label_mut_of {
+    () => { None };
+    ($field:ident) => { $crate::opcodes::Argument::as_label_mut($field) };
+}
+
+/// Defines `Command` along with the table describing each instruction, so
+/// that everything which needs to know about the instructions can be derived
+/// from one place. Meta commands have no source representation, so they
+/// don't appear in the table.
+macro_rules! opcodes {
+    (
+        meta { $($meta:ident),* }
+        $(
+            $(#[$attr:meta])*
+            $name:ident $(($field:ident: $ty:ident))* =>
+                $origin:expr, $imp:ident, $code:expr, $mnemonic:expr,
+                $consumes:expr, $produces:expr;
+        )*
+    ) => {
+        #[derive(PartialEq, Eq, Clone, Debug)]
+        pub enum Command {
+            $($meta,)*
+            $($(#[$attr])* $name $(($ty))*,)*
+        }
+
+        /// Indexes into `OPCODES`, in the same order.
+        #[derive(Clone, Copy)]
+        enum OpcodeIndex {
+            $($(#[$attr])* $name,)*
+        }
+
+        /// Every instruction in the language.
+        pub static OPCODES: &[$crate::opcodes::Opcode] = &[
+            $(
+                $(#[$attr])*
+                $crate::opcodes::Opcode {
+                    origin: $origin,
+                    imp: IMP::$imp,
+                    code: $code,
+                    mnemonic: $mnemonic,
+                    arg: arg_kind!($($ty)*),
+                    effect: $crate::opcodes::StackEffect {
+                        consumes: $consumes,
+                        produces: $produces,
+                    },
+                    build: {
+                        #[allow(unused_variables)]
+                        fn build(operand: $crate::opcodes::Operand) -> Command {
+                            Command::$name $((
+                                <$ty as $crate::opcodes::Argument>::from_operand(operand)
+                            ))*
+                        }
+                        build
+                    },
+                },
+            )*
+        ];
+
+        impl Command {
+            /// The table entry for this command, or `None` for meta commands.
+            pub fn opcode(&self) -> Option<&'static $crate::opcodes::Opcode> {
+                match *self {
+                    $(Command::$meta => None,)*
+                    $($(#[$attr])* Command::$name { .. } => Some(&OPCODES[OpcodeIndex::$name as usize]),)*
+                }
+            }
+
+            /// The argument of this command.
+            pub fn operand(&self) -> $crate::opcodes::Operand {
+                match *self {
+                    $(Command::$meta => operand_of!(),)*
+                    $($(#[$attr])* Command::$name $((ref $field))* => operand_of!($($field)*),)*
+                }
+            }
+
+            /// The label this command marks or jumps to, if any.
+            pub fn label(&self) -> Option<&Label> {
+                match *self {
+                    $(Command::$meta => label_of!(),)*
+                    $($(#[$attr])* Command::$name $((ref $field))* => label_of!($($field)*),)*
+                }
+            }
+
+            /// Mutable access to the label this command marks or jumps to, if any.
+            pub fn label_mut(&mut self) -> Option<&mut Label> {
+                match *self {
+                    $(Command::$meta => label_mut_of!(),)*
+                    $($(#[$attr])* Command::$name $((ref mut $field))* => label_mut_of!($($field)*),)*
+                }
+            }
+        }
+    };
+}
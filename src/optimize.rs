@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+
+use cfg::Cfg;
+use command::Command;
+use depth::{Depth, StackDepths};
+use {Label, Number};
+
+/// The highest optimization level we understand.
+pub const MAX_LEVEL: u8 = 1;
+
+/// The result of an arithmetic command on constants, or `None` if it would
+/// fault at runtime, so has to be left for then.
+pub fn fold(a: Number, b: Number, op: &Command) -> Option<Number> {
+    match *op {
+        Command::Add => Some(a.wrapping_add(b)),
+        Command::Subtract => Some(a.wrapping_sub(b)),
+        Command::Multiply => Some(a.wrapping_mul(b)),
+        // These catch dividing by zero and MIN / -1, which both trap.
+        Command::Divide => a.checked_div(b),
+        Command::Modulus => a.checked_rem(b),
+        _ => None,
+    }
+}
+
+/// Whether the stack is sure to hold at least `n` items, given its depth.
+fn holds(depth: Option<Depth>, n: usize) -> bool {
+    depth.is_some_and(|depth| depth.min >= n)
+}
+
+/// Simplifies the last few commands, returning whether anything changed.
+/// Each comes with the depth of the stack before it. Marks are never part of
+/// a pattern, so nothing is moved across a place which can be jumped to.
+fn rewrite_tail(out: &mut Vec<(Command, Option<Depth>)>) -> bool {
+    let len = out.len();
+    if len >= 3 {
+        if let (&Command::Push(a), &Command::Push(b)) = (&out[len - 3].0, &out[len - 2].0) {
+            if let Some(n) = fold(a, b, &out[len - 1].0) {
+                let depth = out[len - 3].1;
+                out.truncate(len - 3);
+                out.push((Command::Push(n), depth));
+                return true;
+            }
+        }
+    }
+    if len >= 2 {
+        // Duplicating or swapping too short a stack is an error, which
+        // these mustn't hide.
+        let depth = out[len - 2].1;
+        let replacement = match (&out[len - 2].0, &out[len - 1].0) {
+            (Command::Push(_), Command::Pop) => Some(None),
+            (Command::Duplicate, Command::Pop) if holds(depth, 1) => Some(None),
+            (Command::Swap, Command::Swap) if holds(depth, 2) => Some(None),
+            // A tail call: the subroutine can return straight to our caller.
+            (Command::Call(l), Command::Return) => Some(Some((Command::Jump(l.clone()), depth))),
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            out.truncate(len - 2);
+            out.extend(replacement);
+            return true;
+        }
+    }
+    false
+}
+
+/// Folds constant arithmetic and removes commands which cancel out.
+fn peephole(program: Vec<Command>) -> Vec<Command> {
+    let depths = StackDepths::of(&program).depths;
+    let mut out = Vec::with_capacity(program.len());
+    for (command, depth) in program.into_iter().zip(depths) {
+        out.push((command, depth));
+        while rewrite_tail(&mut out) {}
+    }
+    out.into_iter().map(|(command, _)| command).collect()
+}
+
+/// Removes blocks which can never run, such as code after an exit or
+/// subroutines which are never called, and then marks which nothing jumps to.
+fn remove_dead_code(program: Vec<Command>) -> Vec<Command> {
+    let mut live = vec![false; program.len()];
+    {
+        let cfg = Cfg::new(&program);
+        for (block, reachable) in cfg.blocks.iter().zip(cfg.reachable()) {
+            if reachable {
+                for index in block.range.clone() {
+                    live[index] = true;
+                }
+            }
+        }
+    }
+    let program = program.into_iter()
+                         .zip(live)
+                         .filter_map(|(command, live)| if live { Some(command) } else { None })
+                         .collect::<Vec<Command>>();
+
+    let used = program.iter()
+                      .filter(|c| !matches!(*c, Command::Mark(_)))
+                      .filter_map(Command::label)
+                      .cloned()
+                      .collect::<HashSet<Label>>();
+    program.into_iter()
+           .filter(|c| match *c {
+               Command::Mark(ref l) => used.contains(l),
+               _ => true,
+           })
+           .collect()
+}
+
+/// Rewrites a program into an equivalent one which does less work. Level 0
+/// leaves it alone.
+pub fn optimize(mut program: Vec<Command>, level: u8) -> Vec<Command> {
+    if level == 0 {
+        return program;
+    }
+    // Removing a mark can let the peephole pass see more, so keep going
+    // until neither finds anything.
+    loop {
+        let len = program.len();
+        program = peephole(remove_dead_code(program));
+        if program.len() == len {
+            return program;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {l, run_captured};
+    use wsstd::Context;
+
+    #[test]
+    fn test_fold() {
+        let program = vec![Command::Push(2),
+                           Command::Push(3),
+                           Command::Add,
+                           Command::Push(4),
+                           Command::Multiply,
+                           Command::Push(7),
+                           Command::Subtract,
+                           Command::OutputNum];
+        assert_eq!(optimize(program, 1), vec![Command::Push(13), Command::OutputNum]);
+
+        let program = vec![Command::Push(-7), Command::Push(2), Command::Modulus];
+        assert_eq!(optimize(program, 1), vec![Command::Push(-1)]);
+    }
+
+    #[test]
+    fn test_no_fold_faults() {
+        let program = vec![Command::Push(1), Command::Push(0), Command::Divide];
+        assert_eq!(optimize(program.clone(), 1), program);
+
+        let program = vec![Command::Push(Number::MIN),
+                           Command::Push(-1),
+                           Command::Modulus];
+        assert_eq!(optimize(program.clone(), 1), program);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let program = vec![Command::Push(1),
+                           Command::Duplicate,
+                           Command::Swap,
+                           Command::Push(5),
+                           Command::Pop,
+                           Command::Swap,
+                           Command::Duplicate,
+                           Command::Pop,
+                           Command::OutputNum];
+        assert_eq!(optimize(program, 1),
+                   vec![Command::Push(1), Command::Duplicate, Command::OutputNum]);
+    }
+
+    #[test]
+    fn test_no_cancel_underflow() {
+        // Each of these complains about the stack when it's run, and has to
+        // keep doing so.
+        let programs = vec![vec![Command::Duplicate, Command::Pop],
+                            vec![Command::Push(1), Command::Swap, Command::Swap]];
+        for program in programs {
+            assert_eq!(optimize(program.clone(), 1), program);
+        }
+        let program = vec![Command::Push(1), Command::Pop, Command::Duplicate, Command::Pop];
+        assert_eq!(optimize(program, 1), vec![Command::Duplicate, Command::Pop]);
+
+        // Deep enough on every path, even if not by the same amount.
+        let program = vec![Command::Push(1),
+                           Command::Push(2),
+                           Command::Duplicate,
+                           Command::JumpZero(l("0")),
+                           Command::Push(3),
+                           Command::Mark(l("0")),
+                           Command::Swap,
+                           Command::Swap,
+                           Command::Exit];
+        assert_eq!(optimize(program, 1),
+                   vec![Command::Push(1),
+                        Command::Push(2),
+                        Command::Duplicate,
+                        Command::JumpZero(l("0")),
+                        Command::Push(3),
+                        Command::Mark(l("0")),
+                        Command::Exit]);
+    }
+
+    #[test]
+    fn test_not_across_marks() {
+        let program = vec![Command::Push(1), Command::Mark(l("0")), Command::Pop];
+        assert_eq!(peephole(program.clone()), program);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let program = vec![Command::Mark(l("0")), Command::Call(l("1")), Command::Return];
+        assert_eq!(peephole(program), vec![Command::Mark(l("0")), Command::Jump(l("1"))]);
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let program = vec![Command::Push(1),
+                           Command::Call(l("1")),
+                           Command::Jump(l("0")),
+                           Command::OutputNum,
+                           Command::Mark(l("0")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Return,
+                           Command::Mark(l("11")),
+                           Command::OutputNum,
+                           Command::Return];
+        assert_eq!(optimize(program, 1),
+                   vec![Command::Push(1),
+                        Command::Call(l("1")),
+                        Command::Jump(l("0")),
+                        Command::Mark(l("0")),
+                        Command::Exit,
+                        Command::Mark(l("1")),
+                        Command::Return]);
+    }
+
+    #[test]
+    fn test_unused_marks() {
+        // Once the mark is gone, the push and pop cancel out.
+        let program = vec![Command::Push(1),
+                           Command::Mark(l("0")),
+                           Command::Pop,
+                           Command::Exit];
+        assert_eq!(optimize(program, 1), vec![Command::Exit]);
+    }
+
+    #[test]
+    fn test_shrinks_machine_code() {
+        let program = vec![Command::Exit,
+                           Command::Mark(l("0")),
+                           Command::Push(1),
+                           Command::OutputNum];
+        let optimized = optimize(program.clone(), 1);
+        assert_eq!(optimized, vec![Command::Exit]);
+
+        let size = |program| ::link(program, &mut Context::new(), false).0.len();
+        assert!(size(optimized) < size(program));
+    }
+
+    #[test]
+    fn test_level_zero() {
+        let program = vec![Command::Push(1), Command::Pop];
+        assert_eq!(optimize(program.clone(), 0), program);
+    }
+
+    #[test]
+    fn test_differential() {
+        let program = vec![Command::Push(5),
+                           Command::Call(l("1")),
+                           Command::OutputNum,
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Push(2),
+                           Command::Push(3),
+                           Command::Multiply,
+                           Command::Add,
+                           Command::Call(l("0")),
+                           Command::Return,
+                           Command::Mark(l("0")),
+                           Command::Push(9),
+                           Command::Pop,
+                           Command::Duplicate,
+                           Command::Pop,
+                           Command::Return];
+        let optimized = optimize(program.clone(), 1);
+        assert!(optimized.len() < program.len());
+        assert_eq!(run_captured(optimized, b""), run_captured(program, b""));
+    }
+}
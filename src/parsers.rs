@@ -1,10 +1,42 @@
 
+// nom 3's `named!` drops doc comments on the floor, but they're still useful
+// to read here.
+#![allow(unused_doc_comments)]
+
+use nom::{ErrorKind, IResult, Needed};
+
 use command::*;
+use dialect::Dialect;
+use opcodes::{ArgKind, Opcode, Operand, Origin};
 use {Label, Number};
 
-// TODO properly handle "comments" aka non-legal characters, which should be
-// ignored per the spec.
+/// Removes "comments" aka non-legal characters, which should be ignored per
+/// the spec.
+pub fn strip_comments(input: &[u8]) -> Vec<u8> {
+    input.iter()
+         .cloned()
+         .filter(|&c| c == b' ' || c == b'\t' || c == b'\n')
+         .collect()
+}
+
+/// Settings which change what the parser accepts.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Reject extension instructions, even if they're compiled in.
+    pub strict: bool,
+    /// Reject instructions which are newer than this.
+    pub dialect: Dialect,
+}
 
+impl ParseOptions {
+    /// Whether the given instruction may be used.
+    pub fn allows(&self, opcode: &Opcode) -> bool {
+        match opcode.origin {
+            Origin::Standard(since) => since <= self.dialect,
+            Origin::Extension => !self.strict,
+        }
+    }
+}
 
 /// Identifies characters in a literal
 named!(pub literal_char<bool>, map!(
@@ -43,78 +75,84 @@ named!(pub label<Label>, map!(
 ));
 
 /// Identifies an IMP.
-named!(pub imp<IMP>, alt!(
-    map!(tag!(" "), |_| IMP::Stack) |
-    map!(tag!("\n"), |_| IMP::Flow) |
-    map!(tag!("\t "), |_| IMP::Arithmetic) |
-    map!(tag!("\t\t"), |_| IMP::Heap) |
-    map!(tag!("\t\n"), |_| IMP::IO)
-));
+pub fn imp(input: &[u8]) -> IResult<&[u8], IMP> {
+    for imp in IMPS {
+        if input.starts_with(imp.code()) {
+            return IResult::Done(&input[imp.code().len()..], imp.clone());
+        }
+    }
+    if IMPS.iter().any(|imp| imp.code().starts_with(input)) {
+        IResult::Incomplete(Needed::Unknown)
+    } else {
+        IResult::Error(error_position!(ErrorKind::Alt, input))
+    }
+}
+
+/// Identifies an instruction of the given IMP, using the opcode table.
+pub fn instruction<'a>(input: &'a [u8],
+                       imp: IMP,
+                       options: &ParseOptions)
+                       -> IResult<&'a [u8], Command> {
+    let mut opcodes = OPCODES.iter()
+                             .filter(|opcode| opcode.imp == imp && options.allows(opcode));
+    if let Some(opcode) = opcodes.clone().find(|opcode| input.starts_with(opcode.code)) {
+        let rest = &input[opcode.code.len()..];
+        return match opcode.arg {
+            ArgKind::None => IResult::Done(rest, (opcode.build)(Operand::None)),
+            ArgKind::Number => number(rest).map(|n| (opcode.build)(Operand::Number(n))),
+            ArgKind::Label => label(rest).map(|l| (opcode.build)(Operand::Label(l))),
+        };
+    }
+    if opcodes.any(|opcode| opcode.code.starts_with(input)) {
+        IResult::Incomplete(Needed::Unknown)
+    } else {
+        IResult::Error(error_position!(ErrorKind::Alt, input))
+    }
+}
 
 /// Identifies a stack instruction.
-named!(pub stack<Command>, alt!(
-    map!(preceded!(tag!(" "), number), |n| Command::Push(n)) |
-    map!(tag!("\n "), |_| Command::Duplicate) |
-    map!(preceded!(tag!("\t "), number), |n| Command::Copy(n)) |
-    map!(tag!("\n\t"), |_| Command::Swap) |
-    map!(tag!("\n\n"), |_| Command::Pop) |
-    map!(preceded!(tag!("\t\n"), number), |n| Command::Slide(n))
-));
+named!(pub stack<Command>, call!(instruction, IMP::Stack, &ParseOptions::default()));
 
 /// Identifies a arithmetic instruction.
-named!(pub arithmetic<Command>, alt!(
-    map!(tag!("  "), |_| Command::Add) |
-    map!(tag!(" \t"), |_| Command::Subtract) |
-    map!(tag!(" \n"), |_| Command::Multiply) |
-    map!(tag!("\t "), |_| Command::Divide) |
-    map!(tag!("\t\t"), |_| Command::Modulus)
-));
+named!(pub arithmetic<Command>, call!(instruction, IMP::Arithmetic, &ParseOptions::default()));
 
 /// Identifies a heap instruction.
-named!(pub heap<Command>, alt!(
-    map!(tag!(" "), |_| Command::Store) |
-    map!(tag!("\t"), |_| Command::Retrieve)
-));
+named!(pub heap<Command>, call!(instruction, IMP::Heap, &ParseOptions::default()));
 
 /// Identifies a flow control instruction.
-named!(pub flow<Command>, alt!(
-    map!(preceded!(tag!("  "), label), |n| Command::Mark(n)) |
-    map!(preceded!(tag!(" \t"), label), |n| Command::Call(n)) |
-    map!(preceded!(tag!(" \n"), label), |n| Command::Jump(n)) |
-    map!(preceded!(tag!("\t "), label), |n| Command::JumpZero(n)) |
-    map!(preceded!(tag!("\t\t"), label), |n| Command::JumpNegative(n)) |
-    map!(tag!("\t\n"), |_| Command::Return) |
-    map!(tag!("\n\n"), |_| Command::Exit)
-));
+named!(pub flow<Command>, call!(instruction, IMP::Flow, &ParseOptions::default()));
 
 /// Identifies an IO instruction.
-named!(pub io<Command>, alt!(
-    map!(tag!("  "), |_| Command::OutputChar) |
-    map!(tag!(" \t"), |_| Command::OutputNum) |
-    map!(tag!("\t "), |_| Command::ReadChar) |
-    map!(tag!("\t\t"), |_| Command::ReadNum)
-));
+named!(pub io<Command>, call!(instruction, IMP::IO, &ParseOptions::default()));
 
 /// Identifies an entire command.
-named!(pub command<Command>, switch!(imp,
-    IMP::Stack => call!(stack) |
-    IMP::Heap => call!(heap) |
-    IMP::Arithmetic => call!(arithmetic) |
-    IMP::Flow => call!(flow) |
-    IMP::IO => call!(io)
-));
+pub fn command_with<'a>(input: &'a [u8], options: &ParseOptions) -> IResult<&'a [u8], Command> {
+    do_parse!(input,
+        imp: imp >>
+        command: call!(instruction, imp, options) >>
+        (command)
+    )
+}
+
+/// Identifies an entire command, with the default options.
+named!(pub command<Command>, call!(command_with, &ParseOptions::default()));
 
 /// Identifies an entire whitespace program.
-named!(pub program<Vec<Command> >, many0!(command));
+pub fn program_with<'a>(input: &'a [u8],
+                        options: &ParseOptions)
+                        -> IResult<&'a [u8], Vec<Command>> {
+    many0!(input, call!(command_with, options))
+}
+
+/// Identifies an entire whitespace program, with the default options.
+named!(pub program<Vec<Command> >, call!(program_with, &ParseOptions::default()));
 
 #[cfg(test)]
 mod tests {
-    use nom::IResult;
     use super::*;
-    use command::*;
     use Label;
 
-    const NP: &'static str = "string not parsed";
+    const NP: &str = "string not parsed";
 
     macro_rules! nom_match {
         ($parser: ident, $test: expr, $err: expr) => {
@@ -139,6 +177,12 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_strip_comments() {
+        assert_eq!(strip_comments(b"push \t1\n"), b" \t\n".to_vec());
+        assert_eq!(strip_comments(b"nothing"), Vec::<u8>::new());
+    }
+
     #[test]
     fn test_literal_char() {
         nom_match!(literal_char, b"\t", true, "tab not recognized");
@@ -259,6 +303,47 @@ mod tests {
                       "\"\\t\\n \\t\" mistakenly identified as command");
     }
 
+    #[test]
+    fn test_dialect() {
+        let old = ParseOptions {
+            dialect: Dialect::V0_2,
+            ..ParseOptions::default()
+        };
+        // copy 1, then slide 1
+        for source in &[&b" \t  \t\n"[..], &b" \t\n \t\n"[..]] {
+            assert!(command_with(source, &ParseOptions::default()).is_done());
+            assert!(!command_with(source, &old).is_done());
+        }
+        assert!(command_with(b"   \t\n", &old).is_done());
+    }
+
+    #[test]
+    #[cfg(feature = "extensions")]
+    fn test_extensions() {
+        nom_match!(flow, b"\n  ", Command::DumpStack, NP);
+        nom_match!(flow, b"\n \t", Command::DumpHeap, NP);
+        nom_match!(flow, b"\n \n", Command::Breakpoint, NP);
+        nom_match!(flow, b"\n\t ", Command::TraceOn, NP);
+        nom_match!(flow, b"\n\t\t", Command::TraceOff, NP);
+
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert!(!command_with(b"\n\n  ", &strict).is_done(),
+                "extension accepted in strict mode");
+        match command_with(b"\n\n\n", &strict) {
+            IResult::Done(_, Command::Exit) => {}
+            _ => panic!("{}", NP),
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "extensions"))]
+    fn test_no_extensions() {
+        nom_no_match!(flow, b"\n  ", "extension recognized without the feature");
+    }
+
     #[test]
     fn test_program() {
         nom_match!(program,
@@ -0,0 +1,124 @@
+//! Tells `perf` what generated code is, so profiles show where the time went
+//! in the program rather than anonymous addresses. It reads
+//! `/tmp/perf-<pid>.map`, which lists code that isn't in any file as
+//! `start size name`, one per line.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use cfg::Cfg;
+use command::{Command, SourceMap};
+
+/// Where `perf` looks for this process's symbols.
+pub fn path() -> PathBuf {
+    PathBuf::from(format!("/tmp/perf-{}.map", process::id()))
+}
+
+/// A symbol for each basic block of a program, named after the program and
+/// the label the block starts with, and one for the stubs after it. `map`
+/// is as from `command::link_with`, and `len` is the length of all the code.
+pub fn symbols(name: &str, len: usize, map: &SourceMap) -> Vec<(Range<usize>, String)> {
+    let commands = map.iter().map(|(command, _)| command.clone()).collect::<Vec<Command>>();
+    let cfg = Cfg::new(&commands);
+
+    let mut symbols = vec![];
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let code = map[block.range.start].1.start..map[block.range.end - 1].1.end;
+        if code.is_empty() {
+            continue;
+        }
+        let symbol = match block.label {
+            Some(ref label) => format!("{} mark {}", name, label),
+            None => format!("{} block {}", name, id),
+        };
+        symbols.push((code, symbol));
+    }
+    let end = map.last().map_or(0, |(_, range)| range.end);
+    if end < len {
+        symbols.push((end..len, format!("{} stubs", name)));
+    }
+    symbols
+}
+
+/// What to put in the map for a program: one symbol for all of its code,
+/// named after the program, so a profile can show the time spent in it as a
+/// whole, then `symbols` for the pieces of it.
+pub fn entries(name: &str,
+               len: usize,
+               symbols: &[(Range<usize>, String)])
+               -> Vec<(Range<usize>, String)> {
+    let mut entries = vec![(0..len, name.to_string())];
+    entries.extend(symbols.iter().cloned());
+    entries
+}
+
+/// Adds symbols for code at `base` to a map, which may already have other
+/// programs in it.
+pub fn write(path: &Path, base: usize, symbols: &[(Range<usize>, String)]) -> io::Result<()> {
+    let mut out = String::new();
+    for (range, symbol) in symbols {
+        out.push_str(&format!("{:x} {:x} {}\n", base + range.start, range.len(), symbol));
+    }
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(out.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use wsstd::Context;
+    use {l, link};
+
+    #[test]
+    fn test_symbols() {
+        let program = vec![Command::Push(3),
+                           Command::Mark(l("0")),
+                           Command::Push(1),
+                           Command::Subtract,
+                           Command::Duplicate,
+                           Command::JumpZero(l("1")),
+                           Command::OutputNum,
+                           Command::Jump(l("0")),
+                           Command::Mark(l("1")),
+                           Command::Exit];
+        let (code, map) = link(program, &mut Context::new(), false);
+        let symbols = symbols("a.ws", code.len(), &map);
+        let names = symbols.iter().map(|(_, name)| &name[..]).collect::<Vec<_>>();
+        assert_eq!(names,
+                   vec!["a.ws block 0",
+                        "a.ws mark 0",
+                        "a.ws block 2",
+                        "a.ws mark 1",
+                        "a.ws block 4",
+                        "a.ws stubs"]);
+        // They cover all the code, in order.
+        assert_eq!(symbols[0].0.start, 0);
+        assert!(symbols.windows(2).all(|pair| pair[0].0.end == pair[1].0.start));
+        assert_eq!(symbols.last().unwrap().0.end, code.len());
+    }
+
+    #[test]
+    fn test_entries() {
+        let symbols = vec![(0..0x10, "a.ws block 0".to_string()),
+                           (0x10..0x18, "a.ws stubs".to_string())];
+        assert_eq!(entries("a.ws", 0x18, &symbols),
+                   vec![(0..0x18, "a.ws".to_string()),
+                        (0..0x10, "a.ws block 0".to_string()),
+                        (0x10..0x18, "a.ws stubs".to_string())]);
+    }
+
+    #[test]
+    fn test_write() {
+        let path = env::temp_dir().join(format!("whitespace-perf-test-{}.map", process::id()));
+        let _ = fs::remove_file(&path);
+        write(&path, 0x1000, &[(0..0x10, "a.ws mark 0".to_string())]).unwrap();
+        write(&path, 0x2000, &[(0x8..0x20, "b.ws stubs".to_string())]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "1000 10 a.ws mark 0\n2008 18 b.ws stubs\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+//! The Whitespace stack, as the generated code sees it. While a program is
+//! running, `r15` points just past the top item in `Context::stack`'s buffer,
+//! and pushing and popping are plain loads and stores. The context only
+//! finds out about them when we sync, so anything which reads the stack from
+//! Rust has to sync first.
+
+use asm::{Assembler, Cond, Mem};
+use asm::Reg::*;
+use Number;
+
+/// Pushes `rsi`.
+pub fn push(a: &mut Assembler) {
+    let room = a.new_label();
+    a.cmp_mem(R15, field!(native_limit));
+    a.jcc_short(Cond::B, room);
+
+    // twice, to keep the stack aligned
+    a.push(Rsi);
+    a.push(Rsi);
+    a.mov(Rsi, R15);
+    fn_call!(a, GrowNative);
+    a.mov(R15, Rax);
+    a.pop(Rsi);
+    a.pop(Rsi);
+
+    a.bind(room);
+    a.store(Mem::new(R15, 0), Rsi);
+    a.add_imm(R15, 8);
+}
+
+/// Pops into `rax`. Popping an empty stack gives 0, after the context
+/// complains.
+pub fn pop(a: &mut Assembler) {
+    let (pop, done) = (a.new_label(), a.new_label());
+    a.cmp_mem(R15, field!(native_base));
+    a.jcc_short(Cond::A, pop);
+    fn_call!(a, NativeUnderflow, RSI: 0);
+    a.jmp_short(done);
+
+    a.bind(pop);
+    a.sub_imm(R15, 8);
+    a.load(Rax, Mem::new(R15, 0));
+    a.bind(done);
+}
+
+/// Reads the item `n` places below the top into `rax`, or 0 if there's no
+/// such item.
+pub fn peek(a: &mut Assembler, n: Number) {
+    let offset = match n.checked_add(1).and_then(|n| n.checked_mul(-8)) {
+        Some(offset) if n >= 0 && offset >= i32::MIN as Number => offset as i32,
+        _ => return fn_call!(a, NativeUnderflow, RSI: 1),
+    };
+    let (load, done) = (a.new_label(), a.new_label());
+    if a.is_blinding() {
+        a.mov_const(Rax, offset as u64);
+        a.add(Rax, R15);
+    } else {
+        a.lea(Rax, Mem::new(R15, offset));
+    }
+    a.cmp_mem(Rax, field!(native_base));
+    a.jcc_short(Cond::Ae, load);
+    fn_call!(a, NativeUnderflow, RSI: 1);
+    a.jmp_short(done);
+
+    a.bind(load);
+    a.load(Rax, Mem::new(Rax, 0));
+    a.bind(done);
+}
+
+/// Tells the context how big the stack is.
+pub fn sync(a: &mut Assembler) {
+    a.mov(Rsi, R15);
+    fn_call!(a, SyncNative);
+}
+
+/// Points `r15` at the top of the context's stack, which might have moved.
+pub fn enter(a: &mut Assembler) {
+    fn_call!(a, EnterNative);
+    a.mov(R15, Rax);
+}
+
+#[cfg(test)]
+mod tests {
+    use command::Command;
+    use {l, run_captured};
+
+    #[test]
+    fn test_grows() {
+        // Pushes 0 to 9999, so the buffer has to move several times.
+        let program = vec![Command::Push(0),
+                           Command::Mark(l("0")),
+                           Command::Duplicate,
+                           Command::Push(1),
+                           Command::Add,
+                           Command::Duplicate,
+                           Command::Push(9999),
+                           Command::Subtract,
+                           Command::JumpZero(l("1")),
+                           Command::Jump(l("0")),
+                           Command::Mark(l("1")),
+                           Command::Exit];
+        let stack = run_captured(program, b"").stack;
+        assert_eq!(stack, (0..10000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_underflow() {
+        let program = vec![Command::Pop, Command::Copy(3), Command::Copy(-1), Command::Exit];
+        assert_eq!(run_captured(program, b"").stack, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_sync() {
+        // The context sees what the generated code pushed, and the generated
+        // code sees what the context did to the stack.
+        let program = vec![Command::Push(1),
+                           Command::Push(2),
+                           Command::Push(3),
+                           Command::OutputNum,
+                           Command::Slide(1),
+                           Command::Duplicate,
+                           Command::Copy(2),
+                           Command::OutputNum,
+                           Command::Exit];
+        let outcome = run_captured(program, b"");
+        assert_eq!(outcome.stdout, b"31".to_vec());
+        assert_eq!(outcome.stack, vec![1, 3, 3, 1]);
+    }
+}
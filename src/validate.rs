@@ -0,0 +1,286 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use command::Command;
+use depth::StackDepths;
+use dialect::Dialect;
+use opcodes::Origin;
+use Label;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a program, and the index of the command it's about.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: command {}: {}", severity, self.index, self.message)
+    }
+}
+
+/// Where control can go after the command at `index`, not counting calls,
+/// which are handled separately.
+fn successors(program: &[Command], marks: &HashMap<&Label, usize>, index: usize) -> Vec<usize> {
+    let target = |label: &Label| marks.get(label).cloned();
+    match program[index] {
+        Command::Jump(ref l) => target(l).into_iter().collect(),
+        Command::JumpZero(ref l) |
+        Command::JumpNegative(ref l) => {
+            target(l).into_iter().chain(Some(index + 1)).collect()
+        }
+        Command::Return | Command::Exit => vec![],
+        _ => vec![index + 1],
+    }
+}
+
+struct Reachability<'a> {
+    program: &'a [Command],
+    marks: HashMap<&'a Label, usize>,
+    /// Subroutines which can return, by the index of their mark.
+    returns: HashSet<usize>,
+}
+
+impl<'a> Reachability<'a> {
+    /// Every command reachable from `start` without returning, assuming that
+    /// calls to subroutines which can return come back to the next command.
+    fn reachable(&self, start: usize) -> Vec<bool> {
+        let mut seen = vec![false; self.program.len() + 1];
+        let mut todo = vec![start];
+        while let Some(index) = todo.pop() {
+            if seen[index] {
+                continue;
+            }
+            seen[index] = true;
+            if index == self.program.len() {
+                continue;
+            }
+            if let Command::Call(ref l) = self.program[index] {
+                match self.marks.get(l) {
+                    Some(target) if !self.returns.contains(target) => continue,
+                    _ => {}
+                }
+            }
+            todo.extend(successors(self.program, &self.marks, index));
+        }
+        seen
+    }
+
+    /// Works out which subroutines can return. Starting from none and adding
+    /// any which can reach a return settles on the right answer, even with
+    /// recursion.
+    fn find_returns(&mut self) {
+        let targets = self.program
+                          .iter()
+                          .filter_map(|c| match *c {
+                              Command::Call(ref l) => self.marks.get(l).cloned(),
+                              _ => None,
+                          })
+                          .collect::<HashSet<usize>>();
+        loop {
+            let returning = targets.iter()
+                                   .cloned()
+                                   .filter(|&t| !self.returns.contains(&t))
+                                   .filter(|&t| {
+                                       let seen = self.reachable(t);
+                                       self.program
+                                           .iter()
+                                           .enumerate()
+                                           .any(|(i, c)| seen[i] && *c == Command::Return)
+                                   })
+                                   .collect::<Vec<usize>>();
+            if returning.is_empty() {
+                return;
+            }
+            self.returns.extend(returning);
+        }
+    }
+}
+
+/// Checks a program for problems before it's run.
+pub fn validate(program: &[Command], dialect: Dialect) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut error = |index, message| {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            index,
+            message,
+        })
+    };
+
+    for (index, command) in program.iter().enumerate() {
+        if let Some(opcode) = command.opcode() {
+            match opcode.origin {
+                Origin::Standard(since) if since > dialect => {
+                    error(index,
+                          format!("{} needs Whitespace {}, not {}", opcode.mnemonic, since, dialect))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut marks = HashMap::new();
+    for (index, command) in program.iter().enumerate() {
+        if let Command::Mark(ref l) = *command {
+            if let Some(first) = marks.insert(l, index) {
+                error(index, format!("label {} is already marked at command {}", l, first));
+                marks.insert(l, first);
+            }
+        }
+    }
+
+    for (index, command) in program.iter().enumerate() {
+        match *command {
+            Command::Mark(_) => {}
+            _ => {
+                if let Some(l) = command.label() {
+                    if !marks.contains_key(l) {
+                        error(index, format!("label {} is never marked", l));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut reachability = Reachability {
+        program,
+        marks,
+        returns: HashSet::new(),
+    };
+    reachability.find_returns();
+
+    let seen = reachability.reachable(0);
+    for (index, command) in program.iter().enumerate() {
+        if seen[index] && *command == Command::Return {
+            error(index, "return outside of any call".to_string());
+        }
+    }
+    if seen[program.len()] {
+        error(program.len(), "the program can end without an exit".to_string());
+    }
+
+    let depths = StackDepths::of(program);
+    for underflow in depths.underflows {
+        let command = &program[underflow.index];
+        let (consumes, _) = command.stack_effect();
+        let depth = depths.depths[underflow.index].unwrap();
+        diagnostics.push(Diagnostic {
+            severity: if underflow.definite { Severity::Error } else { Severity::Warning },
+            index: underflow.index,
+            message: format!("stack underflow: {} needs {} item{} but the stack has {}",
+                             command,
+                             consumes,
+                             if consumes == 1 { "" } else { "s" },
+                             depth),
+        });
+    }
+
+    diagnostics.sort_by_key(|d| d.index);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use l;
+
+    fn messages(program: &[Command]) -> Vec<(Severity, usize)> {
+        validate(program, Dialect::V0_3)
+            .into_iter()
+            .map(|d| (d.severity, d.index))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean() {
+        let program = vec![Command::Call(l("1")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Push(1),
+                           Command::JumpZero(l("1")),
+                           Command::Return];
+        assert_eq!(messages(&program), vec![]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = vec![Command::Mark(l("1")),
+                           Command::Jump(l("0")),
+                           Command::Mark(l("1")),
+                           Command::Exit];
+        assert_eq!(messages(&program),
+                   vec![(Severity::Error, 1), (Severity::Error, 2)]);
+    }
+
+    #[test]
+    fn test_falls_off_end() {
+        assert_eq!(messages(&[Command::Push(1)]), vec![(Severity::Error, 1)]);
+
+        // The subroutine never returns, so we can't get past the call.
+        let program = vec![Command::Call(l("1")),
+                           Command::Mark(l("1")),
+                           Command::Exit];
+        assert_eq!(messages(&program), vec![]);
+    }
+
+    #[test]
+    fn test_return_outside_call() {
+        let program = vec![Command::Push(0),
+                           Command::JumpZero(l("1")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Return];
+        assert_eq!(messages(&program), vec![(Severity::Error, 4)]);
+    }
+
+    #[test]
+    fn test_recursion() {
+        // A subroutine which only returns after calling itself.
+        let program = vec![Command::Call(l("1")),
+                           Command::Exit,
+                           Command::Mark(l("1")),
+                           Command::Push(0),
+                           Command::JumpZero(l("0")),
+                           Command::Call(l("1")),
+                           Command::Mark(l("0")),
+                           Command::Return];
+        assert_eq!(messages(&program), vec![]);
+    }
+
+    #[test]
+    fn test_underflow() {
+        let program = vec![Command::Push(1), Command::Add, Command::Exit];
+        let diagnostics = validate(&program, Dialect::V0_3);
+        assert_eq!(diagnostics[0].to_string(),
+                   "error: command 1: stack underflow: add needs 2 items but the stack has 1");
+
+        let program = vec![Command::ReadNum,
+                           Command::JumpZero(l("0")),
+                           Command::Push(1),
+                           Command::Mark(l("0")),
+                           Command::Pop,
+                           Command::Exit];
+        assert_eq!(messages(&program),
+                   vec![(Severity::Error, 0), (Severity::Warning, 4)]);
+    }
+
+    #[test]
+    fn test_dialect() {
+        let program = vec![Command::Push(1), Command::Copy(0), Command::Exit];
+        assert!(validate(&program, Dialect::V0_3).is_empty());
+        assert_eq!(validate(&program, Dialect::V0_2)[0].index, 1);
+    }
+}
@@ -5,6 +5,9 @@ use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::fmt;
+use std::ptr;
+#[cfg(feature = "extensions")]
+use std::fs::File;
 use std::io::{self, BufRead, BufReader, Read, Write};
 
 pub type Number = i64;
@@ -17,7 +20,7 @@ pub enum Label {
 }
 
 impl Label {
-    fn replace(self, mapping: &HashMap<Vec<bool>, Address>) -> Option<Label> {
+    pub fn replace(self, mapping: &HashMap<Vec<bool>, Address>) -> Option<Label> {
         if let Label::Name(name) = self {
             mapping.get(&name)
                    .map(|&addr| Label::Translated(addr))
@@ -27,15 +30,179 @@ impl Label {
     }
 }
 
+impl fmt::Display for Label {
+    /// Names are shown as binary, with spaces as 0 and tabs as 1.
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Label::Name(ref bits) => {
+                for &bit in bits {
+                    write!(formatter, "{}", if bit { 1 } else { 0 })?;
+                }
+                Ok(())
+            }
+            Label::Translated(addr) => write!(formatter, "@{:#x}", addr),
+        }
+    }
+}
+
+/// One address in the dense part of the heap. These are 16 bytes, so jit-ed
+/// code can find one with a shift.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Slot {
+    value: Number,
+    present: bool,
+}
+
+/// The heap. Most programs only use small non-negative addresses, so those
+/// live in an array which jit-ed code can index directly, and everything else
+/// lives in a map. An address is only ever in one of them.
+pub struct Heap {
+    dense: Vec<Slot>,
+    sparse: HashMap<Number, Number>,
+    // How many addresses below `DENSE_LIMIT` are stored, in either part.
+    small: usize,
+
+    // The dense part's buffer, for jit-ed code. It only moves when it grows,
+    // which only happens outside jit-ed code.
+    pub native_base: *mut Slot,
+    pub native_len: usize,
+}
+
+impl Heap {
+    /// Addresses below this can be kept in the array.
+    pub const DENSE_LIMIT: Number = 1 << 20;
+
+    pub fn new() -> Self {
+        Heap {
+            dense: Vec::new(),
+            sparse: HashMap::new(),
+            small: 0,
+            native_base: ptr::null_mut(),
+            native_len: 0,
+        }
+    }
+
+    pub fn get(&self, address: Number) -> Option<Number> {
+        if 0 <= address && (address as usize) < self.dense.len() {
+            let slot = self.dense[address as usize];
+            if slot.present { Some(slot.value) } else { None }
+        } else {
+            self.sparse.get(&address).cloned()
+        }
+    }
+
+    pub fn insert(&mut self, address: Number, value: Number) {
+        let index = address as usize;
+        if (0..Heap::DENSE_LIMIT).contains(&address) {
+            if self.get(address).is_none() {
+                self.small += 1;
+            }
+            // Only grow while the array would be about half full, so that
+            // one far off address doesn't take lots of memory.
+            let len = (index + 1).max(self.dense.len() * 2).max(16);
+            if index >= self.dense.len() && len <= 2 * self.small.max(8) {
+                self.grow(len);
+            }
+        }
+        if 0 <= address && index < self.dense.len() {
+            self.dense[index] = Slot {
+                value,
+                present: true,
+            };
+        } else {
+            self.sparse.insert(address, value);
+        }
+    }
+
+    /// Makes the array longer, moving in anything which was past its end.
+    fn grow(&mut self, len: usize) {
+        self.dense.resize(len, Slot::default());
+        let moved = self.sparse
+                        .keys()
+                        .cloned()
+                        .filter(|&address| 0 <= address && (address as usize) < len)
+                        .collect::<Vec<_>>();
+        for address in moved {
+            self.dense[address as usize] = Slot {
+                value: self.sparse.remove(&address).unwrap(),
+                present: true,
+            };
+        }
+        self.native_base = self.dense.as_mut_ptr();
+        self.native_len = self.dense.len();
+    }
+
+    /// Every address and its value, in no particular order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Number, Number)> + 'a {
+        let dense = self.dense
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, slot)| slot.present)
+                        .map(|(address, slot)| (address as Number, slot.value));
+        dense.chain(self.sparse.iter().map(|(&address, &value)| (address, value)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_map(&self) -> HashMap<Number, Number> {
+        self.iter().collect()
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl fmt::Debug for Heap {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        formatter.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq<HashMap<Number, Number>> for Heap {
+    fn eq(&self, other: &HashMap<Number, Number>) -> bool {
+        self.len() == other.len() && self.iter().all(|(a, v)| other.get(&a) == Some(&v))
+    }
+}
+
 /// The context of a running program.
 pub struct Context {
     pub stack: Vec<Number>,
-    pub heap: HashMap<Number, Number>,
+    pub heap: Heap,
     // maps literals to jump-to-able addresses in the function
     pub labels: HashMap<Label, Address>,
 
     stdin: BufReader<Box<dyn Read>>,
     stdout: Rc<RefCell<dyn Write>>,
+
+    // While jit-ed code is running, it keeps a pointer to the top of the
+    // stack and reads and writes `stack`'s buffer directly. These are the
+    // bounds of that buffer, which it checks against.
+    pub native_base: *mut Number,
+    pub native_limit: *mut Number,
+
+    // How many more steps the program can take before it's stopped, if it
+    // was compiled to count them; see `Assembler::limit_steps`. It's stopped
+    // once this goes negative.
+    pub steps_left: i64,
+
+    // Where the debugging extensions write to.
+    #[cfg(feature = "extensions")]
+    debug: Rc<RefCell<dyn Write>>,
+    #[cfg(feature = "extensions")]
+    tracing: bool,
+    /// The disassembly of each command, for tracing.
+    #[cfg(feature = "extensions")]
+    pub trace_names: Vec<String>,
 }
 
 impl fmt::Debug for Context {
@@ -48,15 +215,33 @@ impl fmt::Debug for Context {
     }
 }
 
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
 impl Context {
     /// Create a new context.
     pub fn new() -> Self {
         Context {
             stack: Vec::new(),
-            heap: HashMap::new(),
+            heap: Heap::new(),
             labels: HashMap::new(),
             stdin: BufReader::new(Box::new(io::stdin())),
             stdout: Rc::new(RefCell::new(io::stdout())),
+
+            native_base: ptr::null_mut(),
+            native_limit: ptr::null_mut(),
+            steps_left: 0,
+
+            #[cfg(feature = "extensions")]
+            debug: Rc::new(RefCell::new(io::stderr())),
+            #[cfg(feature = "extensions")]
+            tracing: false,
+            #[cfg(feature = "extensions")]
+            trace_names: Vec::new(),
         }
     }
 
@@ -66,8 +251,8 @@ impl Context {
     }
 
     /// Allows providing stdin; very useful for test cases
-    pub fn provide_stdin(&mut self, inp: &'static str) {
-        self.stdin = BufReader::new(Box::new(inp.as_bytes()));
+    pub fn provide_stdin<T: AsRef<[u8]>>(&mut self, inp: T) {
+        self.stdin = BufReader::new(Box::new(io::Cursor::new(inp.as_ref().to_vec())));
     }
 
     // Marked as unsafe to indicate that they're not meant to be called
@@ -101,17 +286,29 @@ impl Context {
         }
     }
 
+    /// Called from jit-ed code. Removes n items from the stack, keeping the
+    /// top item.
+    pub unsafe extern "C" fn slide_stack(&mut self, arg: Number) {
+        let top = self.pop_stack();
+        let len = self.stack.len().saturating_sub(arg as usize);
+        if self.stack.len() < arg as usize {
+            Context::err("WS slide stack error!");
+        }
+        self.stack.truncate(len);
+        self.stack.push(top);
+    }
+
     /// Called from jit-ed code. Reads the two values on top of the heap and stores
     /// them.
     pub unsafe extern "C" fn store(&mut self) {
         let name = self.stack.get(self.stack.len() - 2).unwrap();
-        let value = self.stack.get(self.stack.len() - 1).unwrap();
+        let value = self.stack.last().unwrap();
         self.heap.insert(*name, *value);
     }
 
     /// Called from jit-ed code. Retrieves data from the heap.
     pub unsafe extern "C" fn retrieve(&self) -> Number {
-        *self.heap.get(&self.stack.get(self.stack.len() - 1).unwrap()).unwrap()
+        self.heap.get(*self.stack.last().unwrap()).unwrap()
     }
 
     /// Called from jit-ed code. Displays data to stdout.
@@ -128,20 +325,117 @@ impl Context {
         }
     }
 
-    /// Called from jit-ed code. Reads data from stdin.
+    /// Called from jit-ed code. Reads data from stdin. Running out of input,
+    /// or reading something which isn't a number, reads 0.
     pub unsafe extern "C" fn read(&mut self, is_char: bool) {
-        let name = self.stack.get(self.stack.len() - 1).unwrap();
+        let name = *self.stack.last().unwrap();
         let mut line = String::new();
-        if is_char {
-            self.stdin.read_line(&mut line).unwrap();
-            self.heap.insert(*name, line.as_bytes()[0] as i64);
-        } else {
-            self.stdin.read_line(&mut line).unwrap();
-            self.heap.insert(*name, i64::from_str_radix(&line, 10).unwrap());
-        }
+        let value = match self.stdin.read_line(&mut line) {
+            Ok(_) if is_char => line.as_bytes().first().map(|&c| c as Number),
+            Ok(_) => line.trim().parse::<Number>().ok(),
+            Err(_) => None,
+        };
+        let value = value.unwrap_or_else(|| {
+            Context::err("WS read error!");
+            0
+        });
+        self.heap.insert(name, value);
+    }
+
+    /// Called from jit-ed code on entry, and whenever something other than
+    /// the jit-ed code might have changed the stack. Makes sure there's room
+    /// to push, and returns the new top of the stack.
+    pub unsafe extern "C" fn enter_native(&mut self) -> *mut Number {
+        self.stack.reserve(16);
+        self.native_base = self.stack.as_mut_ptr();
+        self.native_limit = self.native_base.add(self.stack.capacity());
+        self.native_base.add(self.stack.len())
+    }
+
+    /// Called from jit-ed code before anything else looks at the stack.
+    /// Everything below `top` has been written by the jit-ed code.
+    pub unsafe extern "C" fn sync_native(&mut self, top: *mut Number) {
+        self.stack.set_len(top.offset_from(self.native_base) as usize);
+    }
+
+    /// Called from jit-ed code when the buffer is full. Returns the new top
+    /// of the stack, which has moved if the buffer has.
+    pub unsafe extern "C" fn grow_native(&mut self, top: *mut Number) -> *mut Number {
+        self.sync_native(top);
+        let len = self.stack.len();
+        self.stack.reserve(len);
+        self.enter_native()
+    }
+
+    /// Called from jit-ed code when it pops or peeks past the bottom of the
+    /// stack. Returns the value to use instead.
+    pub unsafe extern "C" fn native_underflow(&self, peek: bool) -> Number {
+        Context::err(if peek { "WS peek stack error!" } else { "WS pop stack error!" });
+        0
     }
 
     fn err(val: &'static str) {
         println!("{}", val);
     }
 }
+
+/// Runtime support for the debugging extensions. These write to stderr rather
+/// than stdout, so they don't get mixed up with the program's output.
+#[cfg(feature = "extensions")]
+#[allow(clippy::missing_safety_doc)]
+impl Context {
+    /// Allows capturing debugging output; very useful for test cases
+    pub fn capture_debug(&mut self, out: Rc<RefCell<dyn Write>>) {
+        self.debug = out;
+    }
+
+    /// Called from jit-ed code. Shows the whole stack, top last.
+    pub unsafe extern "C" fn dump_stack(&mut self) {
+        writeln!(self.debug.borrow_mut(), "stack: {:?}", self.stack).unwrap();
+    }
+
+    /// Called from jit-ed code. Shows the whole heap, in address order.
+    pub unsafe extern "C" fn dump_heap(&mut self) {
+        let mut heap = self.heap.iter().collect::<Vec<_>>();
+        heap.sort();
+        writeln!(self.debug.borrow_mut(), "heap: {:?}", heap).unwrap();
+    }
+
+    /// Called from jit-ed code. Turns tracing on or off.
+    pub unsafe extern "C" fn set_trace(&mut self, on: bool) {
+        self.tracing = on;
+    }
+
+    /// Called from jit-ed code before each command, if the program uses
+    /// tracing at all. Shows the command about to run, and the stack.
+    pub unsafe extern "C" fn trace(&mut self, index: usize) {
+        if self.tracing {
+            let name = self.trace_names.get(index).map(|s| &s[..]).unwrap_or("?");
+            writeln!(self.debug.borrow_mut(), "trace: {:<24} {:?}", name, self.stack).unwrap();
+        }
+    }
+
+    /// Called from jit-ed code. Shows the state of the program, then stops in
+    /// the debugger if there is one.
+    pub unsafe extern "C" fn breakpoint(&mut self) {
+        writeln!(self.debug.borrow_mut(), "breakpoint").unwrap();
+        self.dump_stack();
+        self.dump_heap();
+        if Context::debugger_attached() {
+            libc::raise(libc::SIGTRAP);
+        }
+    }
+
+    fn debugger_attached() -> bool {
+        let mut status = String::new();
+        if File::open("/proc/self/status")
+               .and_then(|mut f| f.read_to_string(&mut status))
+               .is_err() {
+            return false;
+        }
+        status.lines()
+              .find(|line| line.starts_with("TracerPid:"))
+              .map(|line| line["TracerPid:".len()..].trim() != "0")
+              .unwrap_or(false)
+    }
+}